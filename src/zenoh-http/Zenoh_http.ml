@@ -18,6 +18,27 @@ open Httpaf_lwt_unix
 
 module HLC = Apero_time.HLC.Make (Apero_time.Clock_unix)
 
+(* The plugin logs with its own source, so that its level and format can be set independently of the router's *)
+let src = Logs.Src.create "zenoh-http" ~doc:"logs of the zenoh-http plugin"
+module Log = (val Logs.src_log src : Logs.LOG)
+
+(* Report the logs of the plugin as JSON objects (one per line on stderr), and the other logs as before *)
+let set_json_log_format () =
+  let previous = Logs.reporter () in
+  let report s level ~over k msgf =
+    if not (Logs.Src.equal s src) then previous.Logs.report s level ~over k msgf
+    else msgf @@ fun ?header:_ ?tags:_ fmt ->
+      Format.kasprintf (fun msg ->
+          let json = `Assoc [
+            ("time", `Float (Unix.gettimeofday ()));
+            ("level", `String (Logs.level_to_string (Some level)));
+            ("src", `String (Logs.Src.name s));
+            ("msg", `String msg) ] in
+          prerr_endline (Yojson.Safe.to_string json);
+          over (); k ()) fmt
+  in
+  Logs.set_reporter { Logs.report }
+
 let zwrite_kind_put = 0L
 let zwrite_kind_update = 1L
 let zwrite_kind_remove = 2L
@@ -31,46 +52,316 @@ let timestamp0 = HLC.Timestamp.create
   (Option.get @@ Uuid.of_string "00000000-0000-0000-0000-000000000000")
   (Option.get @@ HLC.Timestamp.Time.of_string "0")
 
-let respond ?(body="") ?(headers=Headers.empty) ?(status=`OK) reqd =
-  let headers = Headers.add headers "content-length" (String.length body |> string_of_int) in
+(* All the text produced by the plugin is UTF-8: make it explicit for text content types *)
+let with_charset content_type =
+  match String.split_on_char '/' content_type with
+  | "text" :: _
+  | [ "application" ; "json" ]
+  | [ "application" ; "problem+json" ]
+  | [ "application" ; "javascript" ] -> content_type^"; charset=utf-8"
+  | _ -> content_type
+
+(* The headers added to all the responses (set by run from the configuration) *)
+let response_headers : (string * string) list ref = ref []
+
+(* The access log (if configured by run): the channel it's written to, and if the client address
+   is taken from the X-Forwarded-For header (when the plugin is behind a proxy) *)
+let access_log : (out_channel * bool) option ref = ref None
+
+(* The client addresses of the requests being processed, until they are replied and logged in the access log *)
+let clients : (Reqd.t * Unix.sockaddr) list ref = ref []
+
+(* The requests being processed for each client address (if a per-client concurrency limit is configured),
+   until they are replied *)
+let in_flight : (Reqd.t * string) list ref = ref []
+
+let in_flight_count client = List.length (List.filter (fun (_, c) -> c = client) !in_flight)
+
+let string_of_sockaddr = function
+  | Unix.ADDR_INET (addr, _) -> Unix.string_of_inet_addr addr
+  | Unix.ADDR_UNIX path -> path
+
+(* The results of the recent writes with an Idempotency-Key header (if the cache is enabled by run), by key:
+   their status, content type, body, the fingerprint of the write (see idempotency_fingerprint) and time.
+   A write repeated with the same key is replied with the cached result, without writing again.
+   When the cache is full the oldest result is dropped. *)
+let idempotency_cache : (string, Status.t * string option * string * Digest.t * float) Hashtbl.t = Hashtbl.create 64
+let idempotency_max = ref 0
+let idempotency_ttl = ref 0.
+
+(* The writes being processed with an Idempotency-Key, until their result is cached by respond: their key,
+   their method and resource, and the digest of their body (once it's read) *)
+let idempotent_requests : (Reqd.t * (string * string * Digest.t ref)) list ref = ref []
+
+(* The fingerprint of a write with an Idempotency-Key: a key reused for another method, resource or body
+   is not replied with the result of the first write *)
+let idempotency_fingerprint request body_digest = Digest.string (request^"\n"^body_digest)
+
+(* The digest of a body read line by line is chained over its lines, so that it's computed without keeping the body *)
+let empty_body_digest = Digest.string ""
+let add_line_digest digest line = Digest.string (digest^line)
+
+let body_digest ~lines body =
+  if not lines then Digest.string body
+  else
+    let lines = String.split_on_char '\n' body in
+    let lines = match List.rev lines with "" :: rest -> List.rev rest | _ -> lines in
+    List.fold_left add_line_digest empty_body_digest lines
+
+(* Forget a request in the tables of the requests being processed, once it's replied or failed *)
+let release reqd =
+  let forget l = if !l <> [] then l := List.filter (fun (r, _) -> r != reqd) !l in
+  forget clients; forget in_flight; forget idempotent_requests
+
+(* Keep the digest of [body] if it's the one of a write with an Idempotency-Key *)
+let record_body_digest body digest =
+  match List.find_opt (fun (r, _) -> Reqd.request_body r == body) !idempotent_requests with
+  | Some (_, (_, _, read_digest)) -> read_digest := digest
+  | None -> ()
+
+let find_idempotent_result key =
+  match Hashtbl.find_opt idempotency_cache key with
+  | Some (status, content_type, body, fingerprint, time) when Unix.gettimeofday () -. time <= !idempotency_ttl ->
+    Some (status, content_type, body, fingerprint)
+  | Some _ -> Hashtbl.remove idempotency_cache key; None
+  | None -> None
+
+let cache_idempotent_result key fingerprint status content_type body =
+  if Hashtbl.length idempotency_cache >= !idempotency_max then begin
+    let oldest = Hashtbl.fold (fun key (_, _, _, _, time) oldest ->
+        match oldest with
+        | Some (_, t) when t <= time -> oldest
+        | _ -> Some (key, time)) idempotency_cache None
+    in
+    match oldest with Some (key, _) -> Hashtbl.remove idempotency_cache key | None -> ()
+  end;
+  Hashtbl.replace idempotency_cache key (status, content_type, body, fingerprint, Unix.gettimeofday ())
+
+(* Compare 2 strings in a time that only depends on their lengths (e.g. to check a secret token) *)
+let constant_time_equal a b =
+  String.length a = String.length b &&
+  (let diff = ref 0 in
+   String.iteri (fun i c -> diff := !diff lor (Char.code c lxor Char.code b.[i])) a;
+   !diff = 0)
+
+(* Decode the %XX sequences of an URL path (the invalid sequences are kept as is) *)
+let percent_decode s =
+  let hex c = match c with
+    | '0'..'9' -> Some (Char.code c - Char.code '0')
+    | 'a'..'f' -> Some (Char.code c - Char.code 'a' + 10)
+    | 'A'..'F' -> Some (Char.code c - Char.code 'A' + 10)
+    | _ -> None
+  in
+  let len = String.length s in
+  let b = Buffer.create len in
+  let rec decode i =
+    if i < len then
+      match s.[i] with
+      | '%' when i + 2 < len -> decode_escape i
+      | c -> Buffer.add_char b c; decode (i+1)
+  and decode_escape i =
+    match hex s.[i+1], hex s.[i+2] with
+    | Some h, Some l -> Buffer.add_char b (Char.chr (h * 16 + l)); decode (i+3)
+    | _ -> Buffer.add_char b '%'; decode (i+1)
+  in
+  decode 0;
+  Buffer.contents b
+
+let log_access oc forwarded reqd (status:Status.t) =
+  let req = Reqd.request reqd in
+  let client = match Headers.get req.headers "x-forwarded-for", List.assq_opt reqd !clients with
+    | Some xff, _ when forwarded -> String.trim @@ List.hd @@ String.split_on_char ',' xff
+    | _, Some addr -> string_of_sockaddr addr
+    | _, None -> "-"
+  in
+  let path = fst @@ Astring.span ~sat:(fun c -> c <> '?') req.target in
+  let header name = match Headers.get req.headers name with Some v -> `String v | None -> `Null in
+  let entry = `Assoc [
+    ("time", `Float (Unix.gettimeofday ()));
+    ("client", `String client);
+    ("method", `String (Method.to_string req.meth));
+    ("path", `String (percent_decode path));
+    ("status", `Int (Status.to_code status));
+    ("user_agent", header "user-agent") ] in
+  output_string oc (Yojson.Safe.to_string entry);
+  output_char oc '\n';
+  flush oc;
+  clients := List.filter (fun (r, _) -> r != reqd) !clients
+
+let header_name_regex = Str.regexp "^[-!#$%&'*+.^_`|~0-9A-Za-z]+$"
+
+(* Parse a "Name: Value" header specification *)
+let header_of_string s =
+  match String.index_opt s ':' with
+  | None -> Error ("malformed header (expected \"Name: Value\"): "^s)
+  | Some i ->
+    let name = String.sub s 0 i and value = String.trim (Astring.with_range ~first:(i+1) s) in
+    if not (Str.string_match header_name_regex name 0) then Error ("invalid header name: "^name)
+    else if String.contains value '\r' || String.contains value '\n' then Error ("invalid value for header "^name)
+    else Ok (name, value)
+
+let with_response_headers ?content_type headers =
   let headers = Headers.add headers "Access-Control-Allow-Origin" "*" in
+  let headers = Headers.add_list headers !response_headers in
+  match content_type with
+  | Some t -> Headers.add headers "content-type" (with_charset t)
+  | None -> headers
+
+(* Start a chunked response, whose body is written (until it's closed with close_streaming) on the returned writer.
+   The writer of the reply to a HEAD is closed already (nothing is written on it). *)
+let respond_streaming ?(headers=Headers.empty) ~content_type reqd =
+  let headers = with_response_headers ~content_type (Headers.add headers "transfer-encoding" "chunked") in
+  (match !access_log with Some (oc, forwarded) -> log_access oc forwarded reqd `OK | None -> ());
+  let body = Reqd.respond_with_streaming reqd (Response.create ~headers `OK) in
+  if (Reqd.request reqd).meth = `HEAD then Body.close_writer body;
+  body
+
+(* End a response started with respond_streaming (if the client didn't close it already) *)
+let close_streaming reqd body =
+  if not (Body.is_closed body) then Body.close_writer body;
+  release reqd
+
+(* The reply to a HEAD has the headers of the reply to the same GET, but no body. Its content-length is the one
+   of [body], unless another one is given (e.g. when the body of the GET is not rendered for a HEAD). *)
+let respond ?(body="") ?(headers=Headers.empty) ?content_type ?(status=`OK) ?(content_length=`Body) reqd =
+  let headers = match content_length with
+    | `Body -> Headers.add headers "content-length" (String.length body |> string_of_int)
+    | `Length l -> Headers.add headers "content-length" (string_of_int l)
+    | `Unknown -> headers
+  in
+  let headers = with_response_headers ?content_type headers in
+  (match !access_log with Some (oc, forwarded) -> log_access oc forwarded reqd status | None -> ());
+  (* the server errors are not cached, so that the write can be retried *)
+  (match List.assq_opt reqd !idempotent_requests with
+   | Some (key, request, digest) when not (Status.is_server_error status) ->
+     cache_idempotent_result key (idempotency_fingerprint request !digest) status content_type body
+   | _ -> ());
+  release reqd;
+  let body = if (Reqd.request reqd).meth = `HEAD then "" else body in
   Reqd.respond_with_string reqd (Response.create ~headers status) body
 
+let content_type_of_file path =
+  match Filename.extension path with
+  | ".html" -> Some "text/html"
+  | ".css" -> Some "text/css"
+  | ".js" -> Some "application/javascript"
+  | ".json" -> Some "application/json"
+  | ".svg" -> Some "image/svg+xml"
+  | ".png" -> Some "image/png"
+  | _ -> None
+
 let respond_file path reqd = 
-  try respond ~body:(OCamlRes.Res.find (OCamlRes.Path.of_string path) Resources.root) reqd; true
+  try respond ~body:(OCamlRes.Res.find (OCamlRes.Path.of_string path) Resources.root) ?content_type:(content_type_of_file path) reqd; true
   with Not_found -> 
-  try respond ~body:(OCamlRes.Res.find (OCamlRes.Path.of_string (path^"/index.html")) Resources.root) reqd; true
+  try respond ~body:(OCamlRes.Res.find (OCamlRes.Path.of_string (path^"/index.html")) Resources.root) ~content_type:"text/html" reqd; true
   with Not_found -> false
 
-let respond_internal_error reqd error = respond reqd ~status:`Internal_server_error ~body:
-  ("INTERNAL ERROR: "^error)
+(* The HTML template of the error responses (if configured), where "{{status}}", "{{title}}" and "{{message}}"
+   are replaced by the status code, its reason phrase and the error message *)
+let error_page : string option ref = ref None
+
+let error_page_of_file file =
+  let ic = open_in_bin file in
+  let page = try really_input_string ic (in_channel_length ic) with exn -> close_in ic; raise exn in
+  close_in ic;
+  match Str.search_forward (Str.regexp_string "{{message}}") page 0 with
+  | _ -> page
+  | exception Not_found -> failwith (Printf.sprintf "the error page %s has no {{message}} placeholder" file)
+
+let html_escape s =
+  let b = Buffer.create (String.length s) in
+  String.iter (function
+      | '<' -> Buffer.add_string b "&lt;"
+      | '>' -> Buffer.add_string b "&gt;"
+      | '&' -> Buffer.add_string b "&amp;"
+      | '"' -> Buffer.add_string b "&quot;"
+      | '\'' -> Buffer.add_string b "&#39;"
+      | c -> Buffer.add_char b c) s;
+  Buffer.contents b
+
+(* Respond with an error: as a RFC 7807 problem document if the client accepts JSON, as the configured error page
+   if it accepts HTML, or as plain text otherwise *)
+let respond_error ?headers ?(prefix="") reqd (status : Status.standard) detail =
+  let is_json media_type = match String.trim @@ List.hd @@ String.split_on_char ';' media_type with
+    | "application/json" | "application/problem+json" -> true
+    | _ -> false
+  in
+  match Headers.get (Reqd.request reqd).headers "accept" with
+  | Some accept when List.exists is_json (String.split_on_char ',' accept) ->
+    let problem = `Assoc [
+      ("type", `String "about:blank");
+      ("title", `String (Status.default_reason_phrase status));
+      ("status", `Int (Status.to_code (status :> Status.t)));
+      ("detail", `String detail) ] in
+    respond ?headers reqd ~status:(status :> Status.t) ~content_type:"application/problem+json" ~body:(Yojson.Safe.to_string problem)
+  | Some accept when !error_page <> None
+                  && List.exists (fun t -> String.trim (List.hd (String.split_on_char ';' t)) = "text/html") (String.split_on_char ',' accept) ->
+    (* the values are substituted literally (a replacement text of Str.global_replace would be a template) *)
+    let substitute placeholder value page = Str.global_substitute (Str.regexp_string placeholder) (fun _ -> html_escape value) page in
+    let body = Option.get !error_page
+      |> substitute "{{status}}" (string_of_int (Status.to_code (status :> Status.t)))
+      |> substitute "{{title}}" (Status.default_reason_phrase status)
+      |> substitute "{{message}}" detail in
+    respond ?headers reqd ~status:(status :> Status.t) ~content_type:"text/html" ~body
+  | _ -> respond ?headers reqd ~status:(status :> Status.t) ~content_type:"text/plain" ~body:(prefix^detail)
 
-let respond_unsupported reqd meth path = respond reqd ~status:`Bad_request ~body:
+let respond_internal_error reqd error = respond_error reqd `Internal_server_error ~prefix:"INTERNAL ERROR: " error
+
+let respond_unsupported reqd meth path ~allow = respond_error reqd `Method_not_allowed
+  ~headers:(Headers.of_list [ ("allow", String.concat ", " allow) ])
   ("Operation "^(Method.to_string meth)^" not supported on path: "^path)
 
+let respond_bad_request reqd error = respond_error reqd `Bad_request ~prefix:"BAD REQUEST: " error
+
+let respond_not_found reqd path = respond_error reqd `Not_found ("No resource found for path: "^path)
+
+let respond_forbidden reqd path = respond_error reqd `Forbidden ("Access forbidden to path: "^path)
+
+let respond_unavailable reqd = respond_error reqd `Service_unavailable ~headers:(Headers.of_list [ ("retry-after", "1") ])
+  ~prefix:"SERVICE UNAVAILABLE: " "the zenoh session is not ready yet"
+
+let respond_gateway_timeout reqd resname wait = respond_error reqd `Gateway_timeout
+  (Printf.sprintf "No reply received on %s within %.1fs" resname wait)
+
+let respond_too_large reqd size max = respond_error reqd `Payload_too_large
+  (Printf.sprintf "The response (%d bytes) exceeds the maximum size of %d bytes: use a more selective query" size max)
+
+let respond_unsupported_media_type reqd error = respond_error reqd `Unsupported_media_type ~prefix:"UNSUPPORTED MEDIA TYPE: " error
+
+let respond_not_acceptable reqd charset = respond_error reqd `Not_acceptable
+  ("Charset(s) not supported: "^charset^" (only utf-8 is supported)")
+
+let respond_root_selector reqd = respond_bad_request reqd
+  "the root path is not a selector: a selector is required (e.g. /** for all the resources)"
+
+let respond_uri_too_long reqd length max = respond_error reqd `Uri_too_long
+  (Printf.sprintf "The selector (%d bytes) exceeds the maximum length of %d bytes" length max)
+
+let respond_too_many_requests reqd client max = respond_error reqd `Too_many_requests
+  ~headers:(Headers.of_list [ ("retry-after", "1") ])
+  (Printf.sprintf "Too many concurrent requests from %s (the maximum is %d)" client max)
+
+(* Check if the Accept header of a request explicitly lists [media_type] *)
+let accepts_media_type headers media_type =
+  match Headers.get headers "accept" with
+  | Some accept ->
+    String.split_on_char ',' accept
+    |> List.exists (fun t -> String.trim (List.hd (String.split_on_char ';' t)) = media_type)
+  | None -> false
 
-let json_of_results (results : (string * Abuf.t * Ztypes.data_info) list) =
-  let open Ztypes in
-  (* We assume the value can be decoded as a string *)
-  let read_all_bytes buf = Abuf.read_bytes (Abuf.readable_bytes buf) buf in
-  let string_of_buf = Apero.compose Bytes.to_string read_all_bytes in
-  let json_of_value value encoding =
-    match encoding with
-    | Some e when e=encoding_json -> value
-    | _ -> Printf.sprintf "\"%s\"" value
-  in
-  results
-  |> List.map (fun (resname, buf, info) ->
-      let time = match info.ts with
-        | None -> "None"
-        | Some ts when ts=timestamp0 -> "None"
-        | Some ts -> Timestamp.Time.to_rfc3339 @@ Timestamp.get_time ts
+(* Check an Accept-Charset header (e.g. "iso-8859-1;q=0.5, utf-8") against the only charset the plugin produces *)
+let accepts_utf8 accept_charset =
+  String.split_on_char ',' accept_charset
+  |> List.exists (fun entry ->
+    match String.split_on_char ';' entry |> List.map String.trim with
+    | [] -> false
+    | charset :: params ->
+      let charset = String.lowercase_ascii charset in
+      let rejected = List.exists (fun p ->
+        Astring.is_prefix ~affix:"q=" p
+        && float_of_string_opt (Astring.with_range ~first:2 p) = Some 0.) params
       in
-      Printf.sprintf "{ \"key\": \"%s\",\n  \"value\": %s,\n  \"time\": \"%s\" }"
-        resname  (json_of_value (string_of_buf buf) info.encoding) time
-      )
-  |> String.concat ",\n"
-  |> Printf.sprintf "[\n%s\n]"
+      (charset = "utf-8" || charset = "*") && not rejected)
 
 
 let on_body_read_complete body (action:Abuf.t -> unit) =
@@ -78,11 +369,39 @@ let on_body_read_complete body (action:Abuf.t -> unit) =
     let chunk = Bigstringaf.substring chunk ~off ~len in
     Abuf.write_bytes (Bytes.of_string chunk) buffer;
     Body.schedule_read body ~on_eof:(on_eof buffer) ~on_read:(on_read buffer)
-  and on_eof buffer () = action buffer
+  and on_eof buffer () =
+    record_body_digest body (Digest.string (Abuf.get_bytes ~at:(Abuf.r_pos buffer) (Abuf.readable_bytes buffer) buffer |> Bytes.to_string));
+    action buffer
   in
   let buffer = Abuf.create ~grow:1024 1024 in
   Body.schedule_read body ~on_eof:(on_eof buffer) ~on_read:(on_read buffer)
 
+(* Read [body] line by line. The next chunk of the body is only read once [on_line] has processed
+   all the lines of the current chunk: the memory used is bounded by the size of a line. *)
+let on_body_lines body ~(on_line:string -> unit Lwt.t) ~(on_eof:unit -> unit) =
+  let pending = Buffer.create 1024 and digest = ref empty_body_digest in
+  let rec process_lines chunk =
+    match String.index_opt chunk '\n' with
+    | Some i ->
+      Buffer.add_string pending (String.sub chunk 0 i);
+      let line = Buffer.contents pending in
+      Buffer.clear pending;
+      digest := add_line_digest !digest line;
+      on_line line >>= fun () -> process_lines (Astring.with_range ~first:(i+1) chunk)
+    | None -> Buffer.add_string pending chunk; Lwt.return_unit
+  in
+  let rec on_read chunk ~off ~len =
+    let chunk = Bigstringaf.substring chunk ~off ~len in
+    Lwt.async (fun () -> process_lines chunk >|= fun () ->
+      Body.schedule_read body ~on_eof:on_last ~on_read)
+  and on_last () =
+    if Buffer.length pending > 0 then digest := add_line_digest !digest (Buffer.contents pending);
+    record_body_digest body !digest;
+    Lwt.async (fun () ->
+      (if Buffer.length pending > 0 then on_line (Buffer.contents pending) else Lwt.return_unit) >|= on_eof)
+  in
+  Body.schedule_read body ~on_eof:on_last ~on_read
+
 let media_type_regex =
   (* RFC6838 Media type format:   type "/" [tree "."] subtype ["+" suffix] *[";" parameter]   *)
   Str.regexp @@ Printf.sprintf "^\\(%s\\)/\\(\\(%s\\)\\.\\)?\\(%s\\)\\(\\+\\(%s\\)\\)?\\(;\\(%s\\)\\)?$"
@@ -94,12 +413,22 @@ let decode_media_type s =
   in
   if Str.string_match media_type_regex s 0 then
     (matched_group_option 1) :: (matched_group_option 3) :: (matched_group_option 4) :: (matched_group_option 6) :: (matched_group_option 8) :: []
-  else ( Logs.warn (fun m -> m "[Zhttp] Invalid media type: %s (consider value as RAW encoding)" s); [] )
+  else ( Log.warn (fun m -> m "[Zhttp] Invalid media type: %s (consider value as RAW encoding)" s); [] )
+
+(* Check the content type of a write against the allowed ones (any content type is allowed if the list is empty) *)
+let is_allowed_content_type allowed content_type =
+  match allowed, content_type with
+  | [], _ -> true
+  | _, None -> false
+  | allowed, Some t ->
+    decode_media_type t <> [] &&
+    let essence = String.lowercase_ascii @@ String.trim @@ List.hd @@ String.split_on_char ';' t in
+    List.exists (fun a -> String.lowercase_ascii (String.trim a) = essence) allowed
 
 let encoding_of_content_type = function
   | None -> encoding_raw
   | Some s ->
-    let l = decode_media_type s in Logs.info (fun m -> m "[Zhttp] media type: %s" (String.concat " / " l));
+    let l = decode_media_type s in Log.info (fun m -> m "[Zhttp] media type: %s" (String.concat " / " l));
     match l with
     | [ "text" ; _ ; _ ; _ ; _ ]
     | [ "application" ; _ ; "x-www-form-urlencoded" ; _ ; _ ]
@@ -108,50 +437,1521 @@ let encoding_of_content_type = function
       -> encoding_string
     | [ "application" ; _ ; "json" ; _ ; _ ]
       -> encoding_json
-    | [] -> Logs.warn (fun m -> m "[Zhttp] Invalid media type: %s (consider value as RAW encoding)" s);
+    | [] -> Log.warn (fun m -> m "[Zhttp] Invalid media type: %s (consider value as RAW encoding)" s);
       encoding_raw
-    | _ -> Logs.debug (fun m -> m "[Zhttp] Unknown media type: %s (default as RAW encoding)" s);
+    | _ -> Log.debug (fun m -> m "[Zhttp] Unknown media type: %s (default as RAW encoding)" s);
       encoding_raw
 
 
-let request_handler zenoh zpid (_ : Unix.sockaddr) reqd =
+(* Extract the parameters reserved to the plugin (i.e. prefixed with "__") from a predicate.
+   Those are returned as a (name, value) list (the value being "true" if not specified), with the remaining predicate. *)
+let reserved_params predicate =
+  let params, others = Astring.span ~sat:(fun c -> c <> '(' && c <> '#') predicate in
+  let reserved, params = String.split_on_char '&' params
+    |> List.filter (fun p -> p <> "")
+    |> List.partition (Astring.is_prefix ~affix:"__")
+  in
+  let reserved = List.map (fun p -> match String.index_opt p '=' with
+      | Some i -> (String.sub p 0 i, String.sub p (i+1) (String.length p - i - 1))
+      | None -> (p, "true")
+    ) reserved
+  in
+  (reserved, (String.concat "&" params)^others)
+
+(* The number of parameters of a query (reserved or not), i.e. before its properties and fragment *)
+let count_params query =
+  fst (Astring.span ~sat:(fun c -> c <> '(' && c <> '#') query)
+  |> String.split_on_char '&' |> List.filter (fun p -> p <> "") |> List.length
+
+let is_param_set params name = List.assoc_opt name params = Some "true"
+
+(* The Content-Disposition header of a response downloaded as the file [name] (percent-encoded),
+   or a "results" file with the extension of [content_type] if no name is given *)
+let content_disposition download content_type =
+  let extension = match content_type with
+    | "application/json-seq" -> ".json-seq"
+    | "application/zip" -> ".zip"
+    | "application/octet-stream" -> ".bin"
+    | _ -> ".json"
+  in
+  let name = match percent_decode download with
+    | "" | "true" -> "results"^extension
+    | name -> String.map (fun c -> if c = '"' || c = '\\' || c = '/' || Char.code c < 0x20 then '_' else c) name
+  in
+  ("content-disposition", Printf.sprintf "attachment; filename=\"%s\"" name)
+
+(* Check if [s] is valid UTF-8. If [len] is specified only the [len] first bytes are checked
+   (a character cut at this length being accepted) *)
+let is_utf8 ?(len=max_int) s =
+  let n = min len (String.length s) in
+  let byte i = Char.code s.[i] in
+  let is_cont b = b land 0xC0 = 0x80 in
+  let rec check i =
+    if i >= n then true
+    else
+      let c = byte i in
+      let size =
+        if c < 0x80 then 1
+        else if c >= 0xC2 && c <= 0xDF then 2
+        else if c >= 0xE0 && c <= 0xEF then 3
+        else if c >= 0xF0 && c <= 0xF4 then 4
+        else 0
+      in
+      (* second byte ranges excluding overlong encodings and surrogates *)
+      let second_ok b = match c with
+        | 0xE0 -> b >= 0xA0 && b <= 0xBF
+        | 0xED -> b >= 0x80 && b <= 0x9F
+        | 0xF0 -> b >= 0x90 && b <= 0xBF
+        | 0xF4 -> b >= 0x80 && b <= 0x8F
+        | _ -> is_cont b
+      in
+      if size = 0 || i + size > String.length s then false
+      else if size > 1 && not (second_ok (byte (i+1))) then false
+      else
+        let rec conts k = k >= size || (is_cont (byte (i+k)) && conts (k+1)) in
+        if i + size > n then true else conts 2 && check (i + size)
+  in
+  check 0
+
+let sniff_length = 512
+
+(* Guess the encoding of a value received without content type (conservatively):
+   JSON if it fully parses as an object or an array, string if it starts with UTF-8 text, raw otherwise. *)
+let sniff_encoding buf =
+  let s = Abuf.get_bytes ~at:(Abuf.r_pos buf) (Abuf.readable_bytes buf) buf |> Bytes.to_string in
+  let sample = String.sub s 0 (min sniff_length (String.length s)) in
+  let rec is_text i = i >= String.length sample ||
+    ((sample.[i] >= ' ' || sample.[i] = '\t' || sample.[i] = '\n' || sample.[i] = '\r') && is_text (i+1))
+  in
+  if s = "" || not (is_utf8 ~len:sniff_length s && is_text 0) then encoding_raw
+  else match (String.trim sample).[0] with
+    | '{' | '[' -> (try ignore (Yojson.Safe.from_string s); encoding_json with Yojson.Json_error _ -> encoding_string)
+    | _ -> encoding_string
+    | exception Invalid_argument _ -> encoding_string
+
+(* A value written with a text encoding (string, JSON...) must be valid UTF-8, and a value written with the JSON
+   encoding must be valid JSON (it's rendered as is in the results of the GETs). The error is returned, if any. *)
+let invalid_value encoding buf =
+  let value = Abuf.get_bytes ~at:(Abuf.r_pos buf) (Abuf.readable_bytes buf) buf |> Bytes.to_string in
+  if not (Zhttp_json.is_text_encoding encoding) then None
+  else if not (is_utf8 value) then Some `Invalid_utf8
+  else if encoding <> encoding_json then None
+  else match Yojson.Safe.from_string value with
+    | _ -> None
+    | exception Yojson.Json_error e -> Some (`Invalid_json e)
+
+let invalid_value_message resname = function
+  | `Invalid_utf8 -> "the value written on "^resname^" has a text encoding but is not valid UTF-8"
+  | `Invalid_json e -> "the value written on "^resname^" has the JSON encoding but is not valid JSON: "^e
+
+(* The encoding of a written value: from its content type or, if absent, sniffed from the value itself (unless __no_sniff is set) *)
+let write_encoding params content_type buf =
+  match content_type with
+  | None when not (is_param_set params "__no_sniff") ->
+    let encoding = sniff_encoding buf in
+    Log.debug (fun m -> m "[Zhttp] no content type: sniffed encoding %Ld" encoding);
+    encoding
+  | content_type -> encoding_of_content_type content_type
+
+let is_merge_patch = function
+  | Some s -> (match decode_media_type s with [ "application" ; _ ; "merge-patch" ; "json" ; _ ] -> true | _ -> false)
+  | None -> false
+
+let string_of_buf buf = Abuf.read_bytes (Abuf.readable_bytes buf) buf |> Bytes.to_string
+
+(* Apply the JSON merge-patch [buf] to the current value of [resname] (RFC 7386), returning the merged value.
+   An Error is returned if the patch or the current value are not JSON. *)
+let json_merge_patch zenoh resname buf =
+  match Yojson.Safe.from_string (string_of_buf buf) with
+  | exception Yojson.Json_error _ -> Lwt.return (Error "the merge-patch is not a valid JSON")
+  | patch ->
+    (* the patch applies to the latest value (whatever the storages replying) *)
+    Zenoh_net.lquery zenoh ~consolidation:Ztypes.LatestValue resname "" >|= fun results ->
+    match List.find_opt (fun (name, _, _) -> name = resname) results with
+    | None -> Ok (Zhttp_json.merge_patch `Null patch)
+    | Some (_, value, (info:Ztypes.data_info)) when info.encoding = Some encoding_json ->
+      (match Yojson.Safe.from_string (string_of_buf value) with
+      | exception Yojson.Json_error _ -> Error ("the current value of "^resname^" is not a valid JSON")
+      | current -> Ok (Zhttp_json.merge_patch current patch))
+    | Some _ -> Error ("the current value of "^resname^" is not JSON encoded")
+
+(* Errors raised by Zenoh_net.write that might not occur anymore on a later attempt (e.g. congestion) *)
+let is_transient_error = function
+  | Unix.Unix_error ((Unix.EAGAIN | Unix.EWOULDBLOCK | Unix.EINTR | Unix.ENOBUFS | Unix.ETIMEDOUT), _, _)
+  | Lwt_stream.Full -> true
+  | _ -> false
+
+(* Check if the key [key] matches the [selector], where a "*" stands for any sequence of characters
+   within a segment, and a "**" for any sequence of characters (including '/') *)
+let key_matches selector key =
+  let ls = String.length selector and lk = String.length key in
+  let rec matches i j =
+    if i = ls then j = lk
+    else if selector.[i] = '*' && i + 1 < ls && selector.[i+1] = '*' then
+      let rec any j = matches (i+2) j || (j < lk && any (j+1)) in any j
+    else if selector.[i] = '*' then
+      let rec within j = matches (i+1) j || (j < lk && key.[j] <> '/' && within (j+1)) in within j
+    else j < lk && selector.[i] = key.[j] && matches (i+1) (j+1)
+  in
+  matches 0 0
+
+(* The selectors as arrays of tokens: "**", "*" or a character *)
+let selector_tokens s =
+  let rec tokens i acc =
+    if i >= String.length s then Array.of_list (List.rev acc)
+    else if s.[i] = '*' && i + 1 < String.length s && s.[i+1] = '*' then tokens (i+2) (`Any :: acc)
+    else if s.[i] = '*' then tokens (i+1) (`Segment :: acc)
+    else tokens (i+1) (`Char s.[i] :: acc)
+  in
+  tokens 0 []
+
+(* Memoize a function of the positions in two arrays of tokens (to avoid an exponential matching) *)
+let memoized f =
+  let memo = Hashtbl.create 64 in
+  let rec g i j =
+    match Hashtbl.find_opt memo (i, j) with
+    | Some r -> r
+    | None -> let r = f g i j in Hashtbl.add memo (i, j) r; r
+  in
+  g 0 0
+
+(* Check if the selectors [s] and [s'] intersect, i.e. if a key can match both *)
+let selectors_intersect s s' =
+  let a = selector_tokens s and b = selector_tokens s' in
+  let na = Array.length a and nb = Array.length b in
+  memoized (fun inter i j ->
+      if i = na && j = nb then true
+      else if i < na && a.(i) = `Any then inter (i+1) j || (j < nb && inter i (j+1))
+      else if j < nb && b.(j) = `Any then inter i (j+1) || (i < na && inter (i+1) j)
+      else if i < na && a.(i) = `Segment && inter (i+1) j then true
+      else if j < nb && b.(j) = `Segment && inter i (j+1) then true
+      else if i = na || j = nb then false
+      else match a.(i), b.(j) with
+        | `Segment, `Char c -> c <> '/' && inter i (j+1)
+        | `Char c, `Segment -> c <> '/' && inter (i+1) j
+        | `Char c, `Char c' -> c = c' && inter (i+1) (j+1)
+        | _ -> false)
+
+(* Check if the selector [s] includes the selector [sub], i.e. if all the keys matching [sub] match [s] *)
+let selector_includes s sub =
+  let a = selector_tokens s and b = selector_tokens sub in
+  let na = Array.length a and nb = Array.length b in
+  memoized (fun incl i j ->
+      if i = na && j = nb then true
+      else if i < na && a.(i) = `Any then incl (i+1) j || (j < nb && incl i (j+1))
+      else if i < na && a.(i) = `Segment then
+        incl (i+1) j || (j < nb && (match b.(j) with `Segment -> true | `Char c -> c <> '/' | `Any -> false) && incl i (j+1))
+      else if i = na || j = nb then false
+      else match a.(i), b.(j) with
+        | `Char c, `Char c' -> c = c' && incl (i+1) (j+1)
+        | _ -> false)
+
+(* A selector can be read if it intersects none of the [deny] patterns and, if [allow] is not empty,
+   if it's included in one of its patterns *)
+let is_readable ~allow ~deny selector =
+  not (List.exists (fun p -> selectors_intersect p selector) deny)
+  && (allow = [] || List.exists (fun p -> selector_includes p selector) allow)
+
+(* The rendered GET responses (if the cache is enabled by run), by request target and accepted types:
+   the selector they were queried with, their content type, headers and body, and their times of creation and of last use.
+   A write (or flush) through the plugin drops the responses whose selector matches the written key (the writes
+   of the other zenoh clients are only taken into account after the TTL). When the cache is full,
+   the least recently used response is dropped. *)
+type cached_response = {
+  selector : string;
+  cached_content_type : string;
+  cached_headers : (string * string) list;
+  cached_body : string;
+  cached_at : float;
+  mutable cache_last_used : float;
+}
+
+let response_cache : (string, cached_response) Hashtbl.t = Hashtbl.create 64
+let response_cache_max = ref 0
+
+let find_cached_response key ~max_age =
+  match Hashtbl.find_opt response_cache key with
+  | Some r when Unix.gettimeofday () -. r.cached_at <= max_age -> r.cache_last_used <- Unix.gettimeofday (); Some r
+  | _ -> None
+
+let cache_response key selector cached_content_type cached_headers cached_body =
+  if Hashtbl.length response_cache >= !response_cache_max then begin
+    let oldest = Hashtbl.fold (fun key r oldest ->
+        match oldest with
+        | Some (_, t) when t <= r.cache_last_used -> oldest
+        | _ -> Some (key, r.cache_last_used)) response_cache None
+    in
+    match oldest with Some (key, _) -> Hashtbl.remove response_cache key | None -> ()
+  end;
+  let now = Unix.gettimeofday () in
+  Hashtbl.replace response_cache key { selector; cached_content_type; cached_headers; cached_body; cached_at = now; cache_last_used = now }
+
+let invalidate_cached_responses key =
+  if Hashtbl.length response_cache > 0 then
+    Hashtbl.filter_map_inplace (fun _ r -> if key_matches r.selector key then None else Some r) response_cache
+
+(* The maximum age of a cached response accepted by a request, from its Cache-Control header ("no-cache" being 0) *)
+let request_max_age headers =
+  match Headers.get headers "cache-control" with
+  | None -> None
+  | Some cc ->
+    String.split_on_char ',' cc |> List.map String.trim
+    |> List.fold_left (fun max_age directive ->
+        match directive, max_age with
+        | "no-cache", _ -> Some 0.
+        | d, None when Astring.is_prefix ~affix:"max-age=" d -> float_of_string_opt (Astring.with_range ~first:8 d)
+        | _ -> max_age) None
+
+(* The publishers declared for the recently written resources (if the cache is enabled by run),
+   so that the writes on a same resource reuse its declaration. When the cache is full,
+   the least recently used publisher is undeclared. *)
+let publishers : (string, Zenoh_net.pub Lwt.t * float ref) Hashtbl.t = Hashtbl.create 64
+let publishers_max = ref 0
+
+let publisher zenoh resname =
+  match Hashtbl.find_opt publishers resname with
+  | Some (pub, last_use) -> last_use := Unix.gettimeofday (); pub
+  | None ->
+    if Hashtbl.length publishers >= !publishers_max then begin
+      let oldest = Hashtbl.fold (fun name (_, last_use) oldest ->
+          match oldest with
+          | Some (_, t) when t <= !last_use -> oldest
+          | _ -> Some (name, !last_use)) publishers None
+      in
+      match oldest with
+      | Some (name, _) ->
+        let pub, _ = Hashtbl.find publishers name in
+        Hashtbl.remove publishers name;
+        Lwt.async (fun () -> pub >>= Zenoh_net.unpublish zenoh)
+      | None -> ()
+    end;
+    let pub = Zenoh_net.publish zenoh resname in
+    Hashtbl.add publishers resname (pub, ref (Unix.gettimeofday ()));
+    Lwt.on_failure pub (fun _ -> Hashtbl.remove publishers resname);
+    pub
+
+let zenoh_write zenoh resname ?timestamp ?kind ?encoding buf =
+  invalidate_cached_responses resname;
+  if !publishers_max > 0
+  then publisher zenoh resname >>= fun pub -> Zenoh_net.stream pub ?timestamp ?kind ?encoding buf
+  else Zenoh_net.write zenoh resname ?timestamp ?kind ?encoding buf
+
+let write_retry_initial_delay = 0.1
+
+(* Zenoh_net.write, retried up to [retries] times with an exponential backoff when failing with a transient error *)
+let write_with_retries ~retries zenoh resname ?timestamp ?kind ?encoding buf =
+  let rec attempt n delay =
+    Lwt.catch (fun () -> zenoh_write zenoh resname ?timestamp ?kind ?encoding (Abuf.duplicate buf))
+    (fun e ->
+      if n < retries && is_transient_error e then begin
+        Log.debug (fun m -> m "[Zhttp] Zenoh.write on %s failed (%s): retry %d/%d in %.1fs" resname (Printexc.to_string e) (n+1) retries delay);
+        Lwt_unix.sleep delay >>= fun () -> attempt (n+1) (delay *. 2.)
+      end else
+        Lwt.fail e)
+  in
+  attempt 0 write_retry_initial_delay
+
+
+(* Parse a line of a NDJSON bulk write: {"key": <string>, "value": <JSON value>, "encoding": "raw"|"string"|"json"}.
+   Without "encoding", a string value is written as a string and any other value as JSON. *)
+let bulk_entry_of_line line =
+  match Yojson.Safe.from_string line with
+  | exception Yojson.Json_error e -> Error ("invalid JSON: "^e)
+  | `Assoc fields -> begin
+    let encoding = match List.assoc_opt "encoding" fields, List.assoc_opt "value" fields with
+      | Some (`String "raw"), _ -> Ok encoding_raw
+      | Some (`String "string"), _ -> Ok encoding_string
+      | Some (`String "json"), _ -> Ok encoding_json
+      | Some e, _ -> Error ("unknown encoding: "^(Yojson.Safe.to_string e))
+      | None, Some (`String _) -> Ok encoding_string
+      | None, _ -> Ok encoding_json
+    in
+    match List.assoc_opt "key" fields, List.assoc_opt "value" fields, encoding with
+    | _, _, Error e -> Error e
+    | Some (`String key), Some value, Ok encoding ->
+      let value = match value with
+        | `String s when encoding <> encoding_json -> s
+        | v -> Yojson.Safe.to_string v
+      in
+      Ok (key, value, encoding)
+    | _ -> Error "a \"key\" string and a \"value\" are expected"
+    end
+  | _ -> Error "a JSON object is expected"
+
+(* Parse a consolidation specification: a comma separated list of "<stage>:<mode>" (or only "<mode>" for the reception stage).
+   Only the reception stage (i.e. the consolidation of the replies by Zenoh_net.lquery) is supported,
+   with modes "none" (all the replies are kept) or "latest" (only the latest value for each key is kept). *)
+let consolidation_of_string spec =
+  let mode_of_string = function
+    | "none" -> Ok Ztypes.KeepAll
+    | "latest" -> Ok Ztypes.LatestValue
+    | mode -> Error ("unknown consolidation mode: "^mode)
+  in
+  String.split_on_char ',' spec
+  |> List.fold_left (fun result stage ->
+      match result, String.split_on_char ':' stage with
+      | Error e, _ -> Error e
+      | Ok _, ([ mode ] | [ "reception" ; mode ]) -> mode_of_string mode
+      | Ok _, [ stage ; _ ] -> Error ("unsupported consolidation stage: "^stage)
+      | Ok _, _ -> Error ("malformed consolidation: "^stage)
+    ) (Ok Ztypes.KeepAll)
+
+let string_of_consolidation = function
+  | Ztypes.KeepAll -> "none"
+  | Ztypes.LatestValue -> "latest"
+
+let query_consolidation ~default params =
+  match List.assoc_opt "__consolidation" params with
+  | Some spec -> consolidation_of_string spec
+  | None -> Ok default
+
+let dest_of_string = function
+  | "none" -> Ok Ztypes.No
+  | "best_match" -> Ok Ztypes.Best_match
+  | "all" -> Ok Ztypes.All
+  | dest when Astring.is_prefix ~affix:"complete-" dest -> begin
+    match int_of_string_opt (Astring.with_range ~first:9 dest) with
+    | Some n when n > 0 -> Ok (Ztypes.Complete n)
+    | _ -> Error ("invalid complete query target: "^dest)
+    end
+  | dest -> Error ("unknown query target: "^dest)
+
+let string_of_dest = function
+  | Ztypes.No -> "none"
+  | Ztypes.Best_match -> "best_match"
+  | Ztypes.All -> "all"
+  | Ztypes.Complete n -> "complete-"^(string_of_int n)
+
+(* Parse a query target: a comma separated list of "<kind>:<dest>" with kind "storages" or "evals" (or only "<dest>"
+   for both), and dest "none", "best_match", "all" or "complete-<n>". The kinds that are not specified keep
+   their [default] target. The result is the pair of targets for the storages and for the evals. *)
+let target_of_string ~default spec =
+  let with_dest dest f = match dest_of_string dest with Ok d -> Ok (f d) | Error e -> Error e in
+  String.split_on_char ',' spec
+  |> List.fold_left (fun result target ->
+      match result, String.split_on_char ':' target with
+      | Error e, _ -> Error e
+      | Ok _, [ dest ] -> with_dest dest (fun d -> (d, d))
+      | Ok (_, evals), [ "storages" ; dest ] -> with_dest dest (fun d -> (d, evals))
+      | Ok (storages, _), [ "evals" ; dest ] -> with_dest dest (fun d -> (storages, d))
+      | Ok _, [ kind ; _ ] -> Error ("unknown query target kind: "^kind)
+      | Ok _, _ -> Error ("malformed query target: "^target)
+    ) (Ok default)
+
+let string_of_target (storages, evals) = "storages:"^(string_of_dest storages)^",evals:"^(string_of_dest evals)
+
+let query_target ~default params =
+  match List.assoc_opt "__target" params with
+  | Some spec -> target_of_string ~default spec
+  | None -> Ok default
+
+(* The replies are always buffered before rendering, so they can be sorted if requested *)
+let query_sort params =
+  match List.assoc_opt "__sort" params with
+  | Some order -> (match Zhttp_json.sort_order_of_string order with Ok order -> Ok (Some order) | Error e -> Error e)
+  | None -> Ok None
+
+(* Replace the values of the results by the sub-values pointed by the "__pointer" parameter (a JSON pointer).
+   The results whose value doesn't contain the pointed sub-value are dropped. *)
+let point_results params results =
+  match List.assoc_opt "__pointer" params with
+  | None -> Ok results
+  | Some pointer ->
+    let pointer = percent_decode pointer in
+    if pointer <> "" && pointer.[0] <> '/' then Error (`Bad_request ("invalid JSON pointer: "^pointer)) else
+    List.fold_right (fun (resname, buf, (info:Ztypes.data_info)) results ->
+        match results with
+        | Error e -> Error e
+        | Ok _ when info.encoding <> Some encoding_json -> Error (`Bad_request ("the value of "^resname^" is not JSON"))
+        | Ok results ->
+          match Yojson.Safe.from_string (string_of_buf buf) with
+          | exception Yojson.Json_error e -> Error (`Bad_request ("the value of "^resname^" is invalid JSON: "^e))
+          | json ->
+            match Zhttp_json.json_pointer pointer json with
+            | Some v -> Ok ((resname, Abuf.from_bytes (Bytes.of_string (Yojson.Safe.to_string v)), info)::results)
+            | None -> Ok results
+      ) results (Ok [])
+    |> function
+    | Ok [] -> Error (`Not_found ("no value containing "^pointer))
+    | r -> r
+
+(* The JSON shape of the results of a GET, as specified by the "__shape" parameter *)
+(* With __versions, the results are grouped by source (as with __group=source) *)
+let query_shape params =
+  let group = if is_param_set params "__versions" then Some "source" else List.assoc_opt "__group" params in
+  match List.assoc_opt "__shape" params, group with
+  | Some shape, _ when shape <> "array" && shape <> "map" -> Error ("unknown shape: "^shape^" (expected array or map)")
+  | _, Some group when group <> "source" -> Error ("unknown grouping: "^group^" (expected source)")
+  | Some "map", Some _ -> Error "the map shape can't be grouped"
+  | Some "map", None -> Ok Zhttp_json.json_map_of_results
+  | _, Some _ -> Ok Zhttp_json.json_by_source_of_results
+  | _, None -> Ok Zhttp_json.json_of_results
+
+let rfc3339_regex = Str.regexp
+  "^[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9][Tt ][0-9][0-9]:[0-9][0-9]:[0-9][0-9]\\(\\.[0-9]+\\)?\\([Zz]\\|[-+][0-9][0-9]:[0-9][0-9]\\)$"
+
+(* Add the time range specified by the "__from" and "__to" parameters (RFC3339 timestamps) to a query predicate,
+   as the "starttime" and "stoptime" properties supported by the time-series storages:
+   e.g. "?__from=2020-01-01T00:00:00Z" becomes "?(starttime=2020-01-01T00:00:00Z)".
+   The storages without history just return their current values. The timestamps may be percent-encoded
+   (e.g. "%2B01:00" for a "+01:00" offset). *)
+let time_range_predicate params predicate =
+  let time name = match Option.map (List.assoc_opt name params) percent_decode with
+    | None -> Ok []
+    | Some t when Str.string_match rfc3339_regex t 0 -> Ok [ t ]
+    | Some t -> Error (Printf.sprintf "invalid %s timestamp (RFC3339 expected): %s" name t)
+  in
+  match time "__from", time "__to" with
+  | Error e, _ | _, Error e -> Error e
+  | Ok [], Ok [] -> Ok predicate
+  | Ok from, Ok until ->
+    let props = String.concat ";" (List.map (fun t -> "starttime="^t) from @ List.map (fun t -> "stoptime="^t) until) in
+    let predicate, fragment = Astring.span ~sat:(fun c -> c <> '#') predicate in
+    match String.rindex_opt predicate ')' with
+    | Some i -> Ok ((String.sub predicate 0 i)^";"^props^(Astring.with_range ~first:i predicate)^fragment)
+    | None -> Ok (predicate^"("^props^")"^fragment)
+
+(* Convert the values of the results to the content type selected by the "__convert" parameter (if any).
+   The values already in this content type are kept as is. A value that can't be converted is an error. *)
+let convert_results params results =
+  match List.assoc_opt "__convert" params with
+  | None -> Ok results
+  | Some target ->
+    let target_encoding = encoding_of_content_type (Some target) in
+    List.fold_right (fun (resname, buf, (info:Ztypes.data_info)) results ->
+        let from = Option.get_or_default info.encoding encoding_raw in
+        match results, Zhttp_codec.find_converter ~from ~target with
+        | Error e, _ -> Error e
+        | Ok results, _ when from = target_encoding && from <> encoding_raw -> Ok ((resname, buf, info)::results)
+        | Ok _, None ->
+          Error (`Unsupported (Printf.sprintf "cannot convert the value of %s from %s to %s"
+                                 resname (Zhttp_json.string_of_encoding from) target))
+        | Ok results, Some convert ->
+          match convert (string_of_buf buf) with
+          | Ok value ->
+            Ok ((resname, Abuf.from_bytes (Bytes.of_string value), { info with encoding = Some target_encoding })::results)
+          | Error e -> Error (`Invalid (Printf.sprintf "cannot convert the value of %s to %s: %s" resname target e))
+      ) results (Ok [])
+
+(* Decode the values of the results with the codec selected by the "__decode" parameter (if any).
+   The decoded values are returned as raw values. A value whose encoding the codec doesn't apply to is an error. *)
+let decode_results params results =
+  match List.assoc_opt "__decode" params with
+  | None -> Ok results
+  | Some name when not (Zhttp_codec.exists name) -> Error ("unknown codec: "^name)
+  | Some name ->
+    List.fold_right (fun (resname, buf, (info:Ztypes.data_info)) results ->
+        let encoding = Option.get_or_default info.encoding encoding_raw in
+        match results, Zhttp_codec.find ~encoding name with
+        | Error e, _ -> Error e
+        | Ok _, None ->
+          Error (Printf.sprintf "cannot decode the value of %s with %s: it's not applicable to its %s encoding"
+                   resname name (Zhttp_json.string_of_encoding encoding))
+        | Ok results, Some decode ->
+          match decode (string_of_buf buf) with
+          | Ok value -> Ok ((resname, Abuf.from_bytes (Bytes.of_string value), { info with encoding = Some encoding_raw })::results)
+          | Error e -> Error (Printf.sprintf "cannot decode the value of %s with %s: %s" resname name e)
+      ) results (Ok [])
+
+(* The paths under /@/http are reserved to the plugin's own endpoints.
+   Return the targeted endpoint and the path of the resource it applies to. *)
+let plugin_route path =
+  let poll_path = "/@/http/poll" and pull_path = "/@/http/pull" and upload_path = "/@/http/upload"
+  and tail_path = "/@/http/tail" and diff_path = "/@/http/diff" in
+  if Astring.is_prefix ~affix:(poll_path^"/") path
+  then (`Poll, Astring.with_range ~first:(String.length poll_path) path)
+  else if Astring.is_prefix ~affix:(pull_path^"/") path
+  then (`Pull, Astring.with_range ~first:(String.length pull_path) path)
+  else if Astring.is_prefix ~affix:(upload_path^"/") path
+  then (`Upload, Astring.with_range ~first:(String.length upload_path) path)
+  else if Astring.is_prefix ~affix:(tail_path^"/") path
+  then (`Tail, Astring.with_range ~first:(String.length tail_path) path)
+  else if Astring.is_prefix ~affix:(diff_path^"/") path
+  then (`Diff, Astring.with_range ~first:(String.length diff_path) path)
+  else if path = "/@/http/flush" then (`Flush, path)
+  else if path = "/@/http/bulk" then (`Bulk, path)
+  else if path = "/@/http/config" then (`Config, path)
+  else (`Data, path)
+
+(* The "name=value" arguments of a query string (not starting with "__", that are handled by reserved_params) *)
+let query_args query =
+  String.split_on_char '&' query
+  |> List.fold_left (fun args arg ->
+      match String.index_opt arg '=' with
+      | Some i -> (String.sub arg 0 i, Astring.with_range ~first:(i+1) arg)::args
+      | None -> args) []
+  |> List.rev
+
+(* The options of a write, from the non-reserved arguments of a PUT or PATCH. The only one supported by
+   Zenoh_net.write is "_time": the timestamp of the value (as returned by Timestamp.to_string), instead of
+   the one set by the router. The other arguments are ignored. *)
+let write_timestamp query =
+  match List.assoc_opt "_time" (query_args query) with
+  | None -> Ok None
+  | Some t ->
+    match Ztypes.Timestamp.of_string (percent_decode t) with
+    | Some ts -> Ok (Some ts)
+    | None -> Error ("invalid _time argument: "^t)
+
+(* Remove all the resources matching [selector], returning their number *)
+let flush zenoh selector =
+  Zenoh_net.lquery zenoh selector "" >>= fun results ->
+  let keys = List.sort_uniq String.compare (List.map (fun (key, _, _) -> key) results) in
+  Lwt_list.iter_s (fun key ->
+      Log.debug (fun m -> m "[Zhttp] flush: Zenoh_net.write remove on %s" key);
+      invalidate_cached_responses key;
+      Zenoh_net.write zenoh key empty_buf ~kind:zwrite_kind_remove >|= ignore) keys
+  >|= fun () -> List.length keys
+
+let poll_default_wait = 30.
+let poll_max_wait = 300.
+
+(* The waiting time of a poll, specified in seconds by a "__wait=N" or "__wait=Ns" parameter *)
+let poll_wait ?(default=poll_default_wait) params =
+  match List.assoc_opt "__wait" params with
+  | None -> Some default
+  | Some w ->
+    let w = if Astring.is_suffix ~affix:"s" w then Astring.with_range ~len:(String.length w - 1) w else w in
+    match float_of_string_opt w with
+    | Some w when w >= 0. -> Some (min w poll_max_wait)
+    | _ -> None
+
+(* Wait at most [wait] seconds for the next sample published on [resname].
+   The subscriber used for this is undeclared as soon as a sample is received or the timeout expires.
+   [before] is called once the subscriber is declared, before waiting (e.g. to send a request). *)
+let poll ?(before=Lwt.return) zenoh resname wait =
+  let sample, resolver = Lwt.wait () in
+  let listener name samples =
+    (match samples with
+    | (buf, info)::_ when Lwt.is_sleeping sample -> Lwt.wakeup_later resolver (Some (name, buf, info))
+    | _ -> ());
+    Lwt.return_unit
+  in
+  Zenoh_net.subscribe zenoh resname listener >>= fun sub ->
+  Lwt.finalize
+    (fun () -> before () >>= fun () -> Lwt.pick [ sample; (Lwt_unix.sleep wait >|= fun () -> None) ])
+    (fun () -> Zenoh_net.unsubscribe zenoh sub)
+
+(* The period at which a tail checks if its client disconnected (i.e. if its body was closed) *)
+let tail_check_period = 1.
+
+(* The maximum number of lines a tail keeps while the previous ones are flushed to a slow client *)
+let tail_max_pending = 1024
+
+(* The running tails (by id), with the time of their last write, for the reaper of the idle ones *)
+type running_tail = {
+  tail_resname : string;
+  mutable last_write : float;
+  stop : unit -> unit;
+}
+
+let running_tails : (int, running_tail) Hashtbl.t = Hashtbl.create 16
+let running_tail_id = ref 0
+
+(* Stop the tails that didn't write anything for more than [idle] seconds (e.g. leaked by a disconnection
+   which wasn't detected) *)
+let reap_idle_tails idle =
+  let now = Unix.gettimeofday () in
+  Hashtbl.fold (fun _ t idles -> if now -. t.last_write > idle then t :: idles else idles) running_tails []
+  |> List.iter (fun t -> Log.info (fun m -> m "[Zhttp] stop the tail on %s idle for %.0fs" t.tail_resname (now -. t.last_write)); t.stop ())
+
+(* The predicate asking the time-series storages for all the stored versions (the others return their current value) *)
+let history_predicate = "(starttime=1970-01-01T00:00:00Z)"
+
+(* The [n] latest of the stored versions [results] (as returned by a query with the KeepAll consolidation),
+   in time order. A version (i.e. a key and timestamp) returned by several storages is kept once. *)
+let tail_history n results =
+  let same (k, _, (i:Ztypes.data_info)) (k', _, (i':Ztypes.data_info)) =
+    k = k' && match i.ts, i'.ts with Some ts, Some ts' -> Ztypes.Timestamp.compare ts ts' = 0 | _ -> false
+  in
+  let versions = List.fold_left (fun versions result -> match versions with
+      | last :: _ when same last result -> versions
+      | _ -> result :: versions) [] (Zhttp_json.sort_results `Time results)
+  in
+  let rec take k l acc = match l with x :: l when k > 0 -> take (k-1) l (x :: acc) | _ -> acc in
+  take n versions []
+
+(* Follow the values published on [resname], writing them on [body] as lines (rendered by [line_of])
+   until the client disconnects, or until [count] values were published (if set). The [history] lines are written first.
+   The lines are only written once the previous ones are flushed: the tail is stopped if more than [tail_max_pending]
+   lines are waiting for a client too slow to read them. *)
+let tail ?count zenoh resname history line_of body =
+  incr running_tail_id;
+  let id = !running_tail_id in
+  let finished, finish = Lwt.wait () in
+  let stop () =
+    if Lwt.is_sleeping finished then begin
+      if not (Body.is_closed body) then Body.close_writer body;
+      Lwt.wakeup_later finish ()
+    end
+  in
+  let running = { tail_resname = resname; last_write = Unix.gettimeofday (); stop } in
+  Hashtbl.replace running_tails id running;
+  let pending = Queue.create () and flushing = ref false in
+  let rec write_pending () =
+    if not !flushing && not (Queue.is_empty pending) && not (Body.is_closed body) then begin
+      Queue.iter (fun line -> Body.write_string body line; Body.write_char body '\n') pending;
+      Queue.clear pending;
+      flushing := true;
+      Body.flush body (fun () -> flushing := false; write_pending ())
+    end
+  in
+  let write sample =
+    if not (Body.is_closed body) then begin
+      if Queue.length pending >= tail_max_pending then begin
+        Log.warn (fun m -> m "[Zhttp] stop the tail on %s: more than %d lines not read by the client" resname tail_max_pending);
+        stop ()
+      end else begin
+        running.last_write <- Unix.gettimeofday ();
+        Queue.add (line_of sample) pending;
+        write_pending ()
+      end
+    end
+  in
+  List.iter (fun sample -> Queue.add (line_of sample) pending) history;
+  write_pending ();
+  let remaining = ref count in
+  let publish sample = match !remaining with
+    | None -> write sample
+    | Some 0 -> ()
+    | Some n ->
+      write sample;
+      remaining := Some (n-1);
+      if n = 1 then stop ()
+  in
+  let listener name samples = List.iter (fun (buf, info) -> publish (name, buf, info)) samples; Lwt.return_unit in
+  Lwt.finalize (fun () ->
+    Zenoh_net.subscribe zenoh resname listener >>= fun sub ->
+    let rec wait () = if Body.is_closed body then Lwt.return_unit else Lwt_unix.sleep tail_check_period >>= wait in
+    Lwt.finalize (fun () -> Lwt.pick [ finished; wait () ])
+      (fun () -> Log.debug (fun m -> m "[Zhttp] end of tail on %s" resname); Zenoh_net.unsubscribe zenoh sub))
+    (fun () -> Hashtbl.remove running_tails id; Lwt.return_unit)
+
+(* The difference from the stored value of [resname] (if any) to a [candidate] value: a structural one if both are JSON,
+   a line by line one if both are text, and only their equality otherwise *)
+let json_of_diff resname stored (candidate, candidate_encoding) =
+  let is_text = Zhttp_json.is_text_encoding in
+  let parse s = try Some (Yojson.Safe.from_string s) with Yojson.Json_error _ -> None in
+  let diff = match stored with
+    | None -> []
+    | Some (value, encoding) ->
+      let kind, diff =
+        match (encoding = encoding_json || candidate_encoding = encoding_json), parse value, parse candidate with
+        | true, Some current, Some candidate -> "json", `List (Zhttp_json.json_diff current candidate)
+        | _ when is_text encoding && is_text candidate_encoding ->
+          (* the texts too large to be diffed line by line are only compared for equality *)
+          "text", (match Zhttp_json.line_diff value candidate with
+            | Some lines -> `List (List.map (fun l -> `String l) lines)
+            | None -> `Null)
+        | _ -> "binary", `Null
+      in
+      [ ("kind", `String kind); ("diff", diff) ]
+  in
+  `Assoc ([ ("key", `String resname);
+            ("exists", `Bool (stored <> None));
+            ("equal", `Bool (match stored with Some (value, _) -> value = candidate | None -> false)) ] @ diff)
+
+(* The chunked uploads in progress on /@/http/upload, by resource name: the bytes received so far,
+   and the last time a chunk was received (to drop them once unused for longer than the configured TTL) *)
+type upload = { data : Buffer.t; mutable upload_last_used : float }
+
+let uploads : (string, upload) Hashtbl.t = Hashtbl.create 16
+
+let expire_uploads ttl =
+  let now = Unix.gettimeofday () in
+  Hashtbl.fold (fun resname u l -> if now -. u.upload_last_used > ttl then resname :: l else l) uploads []
+  |> List.iter (Hashtbl.remove uploads)
+
+(* Append a chunk at [offset] of the upload on [resname]. A chunk at offset 0 (re)starts the upload.
+   The size of the upload is returned, or an error with its current size if [offset] is not at its end,
+   or with [max_total] if the uploads in progress would exceed this total size with the chunk. *)
+let append_chunk ~max_total resname offset chunk =
+  let in_progress = Hashtbl.fold (fun name u size ->
+      if name = resname && offset = 0 then size else size + Buffer.length u.data) uploads 0 in
+  let too_large = in_progress + String.length chunk > max_total in
+  match Hashtbl.find_opt uploads resname, offset with
+  | _, 0 when too_large -> Error (`Too_large max_total)
+  | _, 0 ->
+    let data = Buffer.create (String.length chunk) in
+    Buffer.add_string data chunk;
+    Hashtbl.replace uploads resname { data; upload_last_used = Unix.gettimeofday () };
+    Ok (Buffer.length data)
+  | Some u, offset when offset = Buffer.length u.data ->
+    if too_large then Error (`Too_large max_total) else begin
+      Buffer.add_string u.data chunk;
+      u.upload_last_used <- Unix.gettimeofday ();
+      Ok (Buffer.length u.data)
+    end
+  | Some u, _ -> Error (`Offset (Buffer.length u.data))
+  | None, _ -> Error (`Offset 0)
+
+(* The pull subscriptions created on /@/http/pull, by id: the samples received since their last pull,
+   and the last time they were used (to undeclare them once unused for longer than the configured TTL) *)
+type pull_subscription = {
+  pull_resname : string;
+  pull_sub : Zenoh_net.sub;
+  pulled : (string * Abuf.t * Ztypes.data_info) list ref;
+  mutable last_used : float;
+}
+
+let pull_subscriptions : (string, pull_subscription) Hashtbl.t = Hashtbl.create 16
+
+let expire_pull_subscriptions zenoh ttl =
+  let now = Unix.gettimeofday () in
+  let expired = Hashtbl.fold (fun id p l -> if now -. p.last_used > ttl then (id, p) :: l else l) pull_subscriptions [] in
+  Lwt_list.iter_s (fun (id, p) ->
+      Log.debug (fun m -> m "[Zhttp] pull subscription %s on %s expired" id p.pull_resname);
+      Hashtbl.remove pull_subscriptions id;
+      Zenoh_net.unsubscribe zenoh p.pull_sub) expired
+
+let create_pull_subscription zenoh resname =
+  let pulled = ref [] in
+  let listener name samples =
+    pulled := List.rev_append (List.map (fun (buf, info) -> (name, buf, info)) samples) !pulled;
+    Lwt.return_unit
+  in
+  Zenoh_net.subscribe zenoh ~mode:Zenoh_net.pull_mode resname listener >|= fun pull_sub ->
+  let id = Uuid.make () |> Uuid.to_string in
+  Hashtbl.add pull_subscriptions id { pull_resname = resname; pull_sub; pulled; last_used = Unix.gettimeofday () };
+  id
+
+(* Pull the samples of a subscription, then wait [wait] seconds for them to be received.
+   All the samples received since the previous pull are returned (in their reception order). *)
+let pull_samples p wait =
+  p.last_used <- Unix.gettimeofday ();
+  Zenoh_net.pull p.pull_sub >>= fun () ->
+  (if wait > 0. then Lwt_unix.sleep wait else Lwt.return_unit) >|= fun () ->
+  let samples = List.rev !(p.pulled) in
+  p.pulled := [];
+  samples
+
+let content_type_of_encoding = function
+  | Some e when e = encoding_json -> "application/json"
+  | Some e when e = encoding_string -> "text/plain"
+  | _ -> "application/octet-stream"
+
+let unaccepted_content_type accepted content_type =
+  (Option.get_or_default content_type "no content type")^" (accepted: "^(String.concat ", " accepted)^")"
+
+(* Check a value written (by any of the write paths) with [content_type] and [encoding]: the content type must be
+   one of the [accepted] ones (if any), and the value must be valid for its encoding (see invalid_value) *)
+let check_written_value ~accepted content_type encoding buf =
+  if not (is_allowed_content_type accepted content_type) then
+    Error (`Unsupported_media_type (unaccepted_content_type accepted content_type))
+  else match invalid_value encoding buf with
+    | Some error -> Error error
+    | None -> Ok ()
+
+let respond_invalid_value reqd resname = function
+  | `Unsupported_media_type error -> respond_unsupported_media_type reqd error
+  | (`Invalid_utf8 | `Invalid_json _) as error -> respond_bad_request reqd (invalid_value_message resname error)
+
+(* A write requires a concrete resource name, without any wildcard *)
+let is_concrete_key resname = resname <> "" && not (String.contains resname '*')
+
+let normalize_prefix prefix =
+  let prefix = if Astring.is_prefix ~affix:"/" prefix then prefix else "/"^prefix in
+  if Astring.is_suffix ~affix:"/" prefix then Astring.with_range ~len:(String.length prefix - 1) prefix else prefix
+
+(* Strip the base path the plugin is mounted at (behind a reverse proxy) from a request path.
+   None is returned for the paths that are not under the base path. *)
+let strip_base_path base_path path =
+  match base_path with
+  | None -> Some path
+  | Some base when path = base -> Some "/"
+  | Some base when Astring.is_prefix ~affix:(base^"/") path -> Some (Astring.with_range ~first:(String.length base) path)
+  | Some _ -> None
+
+(* With --http-normalize-slash, the sequences of '/' are collapsed in a single '/', and the trailing '/' is removed
+   (except for the root path "/"). E.g. "//a/b//c/" is normalized as "/a/b/c" *)
+let normalize_slash path =
+  let normalized = String.split_on_char '/' path |> List.filter (fun s -> s <> "") |> String.concat "/" in
+  if normalized = "" || Astring.is_prefix ~affix:"/" path then "/"^normalized else normalized
+
+(* Scope a request path under the configured prefix (if any).
+   None is returned for the paths that could escape from the prefix ("..", or not starting with '/') *)
+let is_dotdot segment = Str.global_replace (Str.regexp_case_fold "%2e") "." segment = ".."
+
+let scoped_path prefix path =
+  match prefix with
+  | None -> Some path
+  | Some _ when not (Astring.is_prefix ~affix:"/" path) -> None
+  | Some _ when List.exists is_dotdot (String.split_on_char '/' path) -> None
+  | Some prefix -> Some (prefix^path)
+
+(* The static files are served under /ui/ (if a static directory is configured) *)
+let static_path = "/ui/"
+
+(* The file of the (percent-encoded) [path] relative to [static_path] in the directory [dir], or its index.html
+   if it's a directory. The paths that could escape from [dir] are rejected. *)
+let static_file dir path =
+  let segments = String.split_on_char '/' path |> List.filter (fun s -> s <> "" && s <> ".") |> List.map percent_decode in
+  if List.exists (fun s -> is_dotdot s || String.contains s '/' || String.contains s '\\' || String.contains s '\000') segments
+  then Error `Forbidden
+  else
+    let file = List.fold_left Filename.concat dir segments in
+    let file = if Sys.file_exists file && Sys.is_directory file then Filename.concat file "index.html" else file in
+    if Sys.file_exists file then Ok file else Error `Not_found
+
+(* Serve the file of [path] from the directory [dir] (see static_file) *)
+let respond_static dir path reqd =
+  match static_file dir path with
+  | Error `Forbidden -> Lwt.return (respond_forbidden reqd path)
+  | Error `Not_found -> Lwt.return (respond_not_found reqd path)
+  | Ok file when (Reqd.request reqd).meth = `HEAD ->
+    (* the length of the file, without reading it *)
+    Lwt_unix.stat file >|= fun stats ->
+    respond reqd ~content_length:(`Length stats.Unix.st_size) ?content_type:(content_type_of_file file)
+  | Ok file ->
+    Lwt_io.with_file ~mode:Lwt_io.Input file Lwt_io.read >|= fun body ->
+    respond reqd ~body ?content_type:(content_type_of_file file)
+
+(* The configuration of the plugin (see Zenoh_http_plugin for the corresponding command line options) *)
+type config = {
+  port : int;
+  prefix : string option;
+  write_retries : int;
+  max_value : int option;
+  rewrites : Zhttp_rewrite.rule list;
+  flush_token : string option;
+  headers : (string * string) list;
+  static_dir : string option;
+  log_level : Logs.level option;
+  log_format : [ `Text | `Json ];
+  accept_content_types : string list;
+  default_target : Ztypes.query_dest * Ztypes.query_dest;
+  default_consolidation : Ztypes.replies_consolidation;
+  access_log : string option;
+  access_log_forwarded : bool;
+  publisher_cache : int;
+  max_response : int option;
+  base_path : string option;
+  normalize_slash : bool;
+  per_client_concurrency : int option;
+  pull_ttl : float;
+  max_pull_subscriptions : int;
+  read_port : int option;
+  write_port : int option;
+  max_selector : int;
+  upload_ttl : float;
+  max_uploads : int;
+  idempotency_cache : int;
+  idempotency_ttl : float;
+  cache_ttl : float option;
+  cache_size : int;
+  read_allow : string list;
+  read_deny : string list;
+  slow_query_ms : int option;
+  max_params : int;
+  error_page : string option;
+  query_timeout_ms : int option;
+  query_timeout_max_ms : int;
+  selftest : string option;
+  selftest_abort : bool;
+  tail_idle_timeout : float option;
+  tail_reaper_period : float;
+}
+
+let default_config = {
+  port = 8000; prefix = None; write_retries = 0; max_value = None; rewrites = []; flush_token = None; headers = [];
+  static_dir = None; log_level = None; log_format = `Text;
+  accept_content_types = []; default_target = (Ztypes.Best_match, Ztypes.Best_match); default_consolidation = Ztypes.KeepAll;
+  access_log = None; access_log_forwarded = false; publisher_cache = 0;
+  max_response = None; base_path = None; normalize_slash = false;
+  per_client_concurrency = None; pull_ttl = 60.; max_pull_subscriptions = 1024;
+  read_port = None; write_port = None; max_selector = 8192;
+  upload_ttl = 300.; max_uploads = 64 * 1024 * 1024; idempotency_cache = 0; idempotency_ttl = 300.;
+  cache_ttl = None; cache_size = 1000; read_allow = []; read_deny = [];
+  slow_query_ms = None; max_params = 256; error_page = None;
+  query_timeout_ms = None; query_timeout_max_ms = 60000;
+  selftest = None; selftest_abort = false;
+  tail_idle_timeout = None; tail_reaper_period = 60. }
+
+(* A JSON snapshot of the configuration (with the flush token redacted) *)
+let json_of_config config =
+  let string_option = function Some s -> `String s | None -> `Null in
+  let target (storages, evals) = `Assoc [ ("storages", `String (string_of_dest storages)); ("evals", `String (string_of_dest evals)) ] in
+  let rewrite (r:Zhttp_rewrite.rule) =
+    `Assoc [ ("pattern", `String (String.concat "/" r.pattern)); ("target", `String (String.concat "/" r.target)) ] in
+  `Assoc [
+    ("port", `Int config.port);
+    ("prefix", string_option config.prefix);
+    ("write_retries", `Int config.write_retries);
+    ("max_value", match config.max_value with Some m -> `Int m | None -> `Null);
+    ("rewrites", `List (List.map rewrite config.rewrites));
+    ("flush_token", match config.flush_token with Some _ -> `String "<redacted>" | None -> `Null);
+    ("headers", `List (List.map (fun (name, value) -> `String (name^": "^value)) config.headers));
+    ("static_dir", string_option config.static_dir);
+    ("log_level", string_option (match config.log_level with Some l -> Some (Logs.level_to_string (Some l)) | None -> None));
+    ("log_format", `String (match config.log_format with `Text -> "text" | `Json -> "json"));
+    ("accept_content_types", `List (List.map (fun t -> `String t) config.accept_content_types));
+    ("default_target", target config.default_target);
+    ("default_consolidation", `String (string_of_consolidation config.default_consolidation));
+    ("access_log", string_option config.access_log);
+    ("access_log_forwarded", `Bool config.access_log_forwarded);
+    ("publisher_cache", `Int config.publisher_cache);
+    ("max_response", match config.max_response with Some m -> `Int m | None -> `Null);
+    ("base_path", string_option config.base_path);
+    ("normalize_slash", `Bool config.normalize_slash);
+    ("per_client_concurrency", match config.per_client_concurrency with Some m -> `Int m | None -> `Null);
+    ("pull_ttl", `Float config.pull_ttl);
+    ("max_pull_subscriptions", `Int config.max_pull_subscriptions);
+    ("read_port", match config.read_port with Some p -> `Int p | None -> `Null);
+    ("write_port", match config.write_port with Some p -> `Int p | None -> `Null);
+    ("max_selector", `Int config.max_selector);
+    ("upload_ttl", `Float config.upload_ttl);
+    ("max_uploads", `Int config.max_uploads);
+    ("idempotency_cache", `Int config.idempotency_cache);
+    ("idempotency_ttl", `Float config.idempotency_ttl);
+    ("cache_ttl", match config.cache_ttl with Some t -> `Float t | None -> `Null);
+    ("cache_size", `Int config.cache_size);
+    ("read_allow", `List (List.map (fun p -> `String p) config.read_allow));
+    ("read_deny", `List (List.map (fun p -> `String p) config.read_deny));
+    ("slow_query_ms", match config.slow_query_ms with Some t -> `Int t | None -> `Null);
+    ("max_params", `Int config.max_params);
+    ("error_page", `Bool (config.error_page <> None));
+    ("query_timeout_ms", match config.query_timeout_ms with Some t -> `Int t | None -> `Null);
+    ("query_timeout_max_ms", `Int config.query_timeout_max_ms);
+    ("selftest", match config.selftest with Some p -> `String p | None -> `Null);
+    ("selftest_abort", `Bool config.selftest_abort);
+    ("tail_idle_timeout", match config.tail_idle_timeout with Some t -> `Float t | None -> `Null);
+    ("tail_reaper_period", `Float config.tail_reaper_period);
+  ]
+
+(* Stream the results of a query as a zip archive (see Zhttp_zip), each entry being written as soon as its reply is
+   received. The replies are not consolidated: only the first one of each key is written. The response is started with
+   the first entry, so that a query whose archive exceeds [max_size] (or the limits of the zip format) is refused with 413,
+   or times out with 504, until then. After that the response is aborted (so that the client doesn't get a corrupt archive). *)
+let respond_zip_query zenoh reqd ~dest_storages ~dest_evals ?max_size ?timeout ~headers resname predicate =
+  let archive = Zhttp_zip.create ?max_size () in
+  let keys = Hashtbl.create 64 in
+  let body = ref None in
+  let start () = match !body with
+    | Some body -> body
+    | None -> let b = respond_streaming reqd ~content_type:"application/zip" ~headers in body := Some b; b
+  in
+  let write s = let b = start () in if not (Body.is_closed b) then Body.write_string b s in
+  let rec next stream = Lwt_stream.get stream >>= function
+    | Some (Zenoh_net.StorageData { resname; data; _ } | Zenoh_net.EvalData { resname; data; _ }) when not (Hashtbl.mem keys resname) ->
+      Hashtbl.replace keys resname ();
+      (match Zhttp_zip.entry archive resname (string_of_buf data) with
+       | Ok entry -> write entry; next stream
+       | Error error -> Lwt.return (Error (`Too_large error)))
+    | Some _ -> next stream
+    | None -> Lwt.return (Ok ())
+  in
+  let query = next (Zenoh_net.squery zenoh ~dest_storages ~dest_evals resname predicate) in
+  (match timeout with
+   | Some ms -> Lwt.pick [ query; (Lwt_unix.sleep (float_of_int ms /. 1000.) >|= fun () -> Error (`Timeout ms)) ]
+   | None -> query) >|= function
+  | Ok () -> write (Zhttp_zip.finish archive); close_streaming reqd (start ())
+  | Error (`Too_large error) when !body = None ->
+    respond_error reqd `Payload_too_large ("The zip archive of the results of "^resname^" exceeds its limits: "^error)
+  | Error (`Timeout ms) when !body = None ->
+    respond_error reqd `Gateway_timeout (Printf.sprintf "No complete reply received for %s within %dms" resname ms)
+  | Error reason ->
+    let error = match reason with `Too_large error -> error | `Timeout ms -> Printf.sprintf "timeout after %dms" ms in
+    Log.warn (fun m -> m "[Zhttp] zip archive of %s aborted: %s" resname error);
+    release reqd;
+    Reqd.report_exn reqd (Failure ("zip archive aborted: "^error))
+
+(* The interpretation of a request (for __echo): what would be queried or written, without doing it *)
+let json_of_request config (req:Request.t) resname predicate params =
+  let string_option = function Some s -> `String s | None -> `Null in
+  let result to_string = function Ok v -> `String (to_string v) | Error e -> `Assoc [ ("error", `String e) ] in
+  let content_type = Headers.get req.headers "content-type" in
+  `Assoc [
+    ("method", `String (Method.to_string req.meth));
+    ("resname", `String resname);
+    ("predicate", `String predicate);
+    ("params", `Assoc (List.map (fun (name, value) -> (name, `String value)) params));
+    ("target", result string_of_target (query_target ~default:config.default_target params));
+    ("consolidation", result string_of_consolidation (query_consolidation ~default:config.default_consolidation params));
+    ("content_type", string_option content_type);
+    (* without content type, the encoding of a written value is sniffed from the value *)
+    ("encoding", match content_type with
+      | Some _ -> `String (Zhttp_json.string_of_encoding (encoding_of_content_type content_type))
+      | None -> `Null);
+  ]
+
+let request_handler zenoh zpid config addr reqd =
   let req = Reqd.request reqd in
-  Logs.debug (fun m -> m "[Zhttp] HTTP req: %a on %s with headers: %a"
+  Log.debug (fun m -> m "[Zhttp] HTTP req: %a on %s with headers: %a"
                                   Method.pp_hum req.meth req.target
                                   Headers.pp_hum req.headers);
-  let resname, predicate = Astring.span ~sat:(fun c -> c <> '?') req.target in
+  let path, predicate = Astring.span ~sat:(fun c -> c <> '?') req.target in
+  match strip_base_path config.base_path path with
+  | None -> respond_not_found reqd path
+  | Some path ->
+  let path = if config.normalize_slash then normalize_slash path else path in
+  let params_count = count_params @@ Astring.with_range ~first:1 predicate in
+  let params, predicate = reserved_params @@ Astring.with_range ~first:1 predicate in
+  let route, path = match config.static_dir, plugin_route path with
+    | Some _, (`Data, _) when Astring.is_prefix ~affix:static_path path ->
+      (* the path relative to the static directory keeps its leading '/' (as expected by scoped_path) *)
+      (`Static, Astring.with_range ~first:(String.length static_path - 1) path)
+    | _, (route, path) -> (route, Zhttp_rewrite.rewrite config.rewrites path)
+  in
+  let max_value = if is_param_set params "__full" then None else config.max_value in
+  let timestamp, timestamp_error = match write_timestamp predicate with Ok ts -> ts, None | Error e -> None, Some e in
+  match scoped_path config.prefix path with
+  | None -> respond_forbidden reqd path
+  | Some resname ->
   let resname =
     if Astring.is_prefix ~affix:"/@/router/local" resname
     then "/@/router/"^zpid^(Astring.with_index_range ~first:15 resname)
     else resname
   in
-  let predicate = Astring.with_range ~first:1 predicate in
+  (* the polls and tails are long-lived: they're not counted in the concurrent requests of a client *)
+  let client = string_of_sockaddr addr in
+  let long_lived = route = `Poll || route = `Tail in
+  match config.per_client_concurrency with
+  | Some max when not long_lived && in_flight_count client >= max -> respond_too_many_requests reqd client max
+  | limit ->
+  if limit <> None && not long_lived then in_flight := (reqd, client) :: !in_flight;
+  (* a write with an Idempotency-Key that was already processed is replied with its cached result (if it's the same
+     write), and a write with the key of a write still being processed is rejected (it would write a second time) *)
+  let idempotency = match req.meth, Headers.get req.headers "idempotency-key" with
+    | (`PUT | `POST | `DELETE | `Other "PATCH"), Some key when !idempotency_max > 0 ->
+      Some (key, Method.to_string req.meth^" "^resname)
+    | _ -> None
+  in
+  let processing key = List.exists (fun (_, (k, _, _)) -> k = key) !idempotent_requests in
+  match idempotency, (match idempotency with Some (key, _) -> find_idempotent_result key | None -> None) with
+  | Some (key, _), _ when processing key ->
+    respond_error reqd `Conflict ("A write with the Idempotency-Key "^key^" is still being processed")
+  | Some (key, request), Some (status, content_type, body, fingerprint) ->
+    on_body_read_complete (Reqd.request_body reqd) (fun buf ->
+      let digest = body_digest ~lines:(route = `Bulk) (string_of_buf buf) in
+      if idempotency_fingerprint request digest = fingerprint then
+        respond reqd ~status ?content_type ~body ~headers:(Headers.of_list [ ("idempotent-replayed", "true") ])
+      else
+        respond reqd ~status:(`Code 422) ~content_type:"text/plain"
+          ~body:("The Idempotency-Key "^key^" was used for another write (with another method, resource or body)"))
+  | _ ->
+  (match idempotency with
+   | Some (key, request) -> idempotent_requests := (reqd, (key, request, ref empty_body_digest)) :: !idempotent_requests
+   | None -> ());
   try begin
       match req.meth with
-      | `GET -> begin
+      (* TRACE is never supported (not even with __echo), so that no request is reflected *)
+      | `TRACE -> respond_unsupported reqd req.meth path ~allow:[ "GET"; "HEAD"; "PUT"; "PATCH"; "POST"; "DELETE" ]
+      | _ when is_param_set params "__echo" ->
+        respond reqd ~content_type:"application/json"
+          ~body:(Yojson.Safe.pretty_to_string (json_of_request config req resname predicate params))
+      | _ when (match route, req.meth with (`Data, (`GET | `HEAD)) | ((`Poll | `Pull | `Tail | `Diff), _) -> true | _ -> false)
+               && not (is_readable ~allow:config.read_allow ~deny:config.read_deny resname) ->
+        respond_forbidden reqd path
+      | `GET when route = `Config ->
+        respond reqd ~content_type:"application/json" ~body:(Yojson.Safe.pretty_to_string (json_of_config config))
+      | _ when route = `Config -> respond_unsupported reqd req.meth path ~allow:[ "GET" ]
+      | (`GET | `HEAD) when route = `Static -> begin
+        let dir = Option.get config.static_dir in
+        Lwt.async (fun _ ->
+          Lwt.catch (fun () -> respond_static dir path reqd)
+            (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+        end
+      | _ when route = `Static -> respond_unsupported reqd req.meth path ~allow:[ "GET"; "HEAD" ]
+      | `DELETE when route = `Flush -> begin
+        (* the flush of a subtree is only enabled if a token is configured, and must be confirmed with it *)
+        let args = query_args predicate in
+        match config.flush_token, Option.map (List.assoc_opt "confirm" args) percent_decode with
+        | Some token, Some confirm when constant_time_equal confirm token -> begin
+          let selector = match Option.map (List.assoc_opt "prefix" args) percent_decode with
+            | Some p ->
+              let p = if config.normalize_slash then normalize_slash p else p in
+              scoped_path config.prefix (Zhttp_rewrite.rewrite config.rewrites p)
+            | None -> None
+          in
+          match selector with
+          | None -> respond_bad_request reqd "missing or invalid prefix argument"
+          | Some selector ->
+            Lwt.async (fun _ ->
+              Lwt.catch
+                (fun () -> flush zenoh selector >|= fun count ->
+                  Log.info (fun m -> m "[Zhttp] flushed %d resources matching %s" count selector);
+                  respond reqd ~content_type:"application/json" ~body:(Printf.sprintf "{ \"removed\": %d }" count))
+                (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+          end
+        | _ -> respond_forbidden reqd path
+        end
+      | `POST when route = `Bulk -> begin
+        match Headers.get req.headers "content-type" with
+        | Some t when not (Astring.is_prefix ~affix:"application/x-ndjson" t) ->
+          respond_unsupported_media_type reqd (t^" (application/x-ndjson is expected)")
+        | _ ->
+          (* each line is written as soon as it's read; the errors are reported in a summary at the end *)
+          let written = ref 0 and errors = ref [] and lineno = ref 0 in
+          let on_line line =
+            incr lineno;
+            let n = !lineno in
+            let error e = errors := (n, e) :: !errors; Lwt.return_unit in
+            if String.trim line = "" then Lwt.return_unit else
+            match bulk_entry_of_line line with
+            | Error e -> error e
+            | Ok (key, value, encoding) ->
+              match scoped_path config.prefix (Zhttp_rewrite.rewrite config.rewrites key) with
+              | None -> error ("access forbidden to key: "^key)
+              | Some resname when not (is_concrete_key resname) -> error ("writes require a concrete key, without wildcard: "^key)
+              | Some resname ->
+                let content_type = content_type_of_encoding (Some encoding) in
+                match check_written_value ~accepted:config.accept_content_types (Some content_type) encoding
+                        (Abuf.from_bytes (Bytes.of_string value)) with
+                | Error (`Unsupported_media_type e) -> error ("unsupported content type: "^e)
+                | Error ((`Invalid_utf8 | `Invalid_json _) as e) -> error (invalid_value_message key e)
+                | Ok () ->
+                Lwt.catch
+                  (fun () -> write_with_retries ~retries:config.write_retries zenoh resname
+                      (Abuf.from_bytes (Bytes.of_string value)) ~kind:zwrite_kind_put ~encoding >|= fun _ -> incr written)
+                  (fun exn -> error (Printexc.to_string exn))
+          in
+          let on_eof () =
+            let json_of_error (line, error) = `Assoc [ ("line", `Int line); ("error", `String error) ] in
+            let summary = `Assoc [ ("written", `Int !written); ("errors", `List (List.rev_map json_of_error !errors)) ] in
+            respond reqd ~content_type:"application/json" ~body:(Yojson.Safe.to_string summary)
+          in
+          on_body_lines (Reqd.request_body reqd) ~on_line ~on_eof
+        end
+      | _ when route = `Bulk -> respond_unsupported reqd req.meth path ~allow:[ "POST" ]
+      | _ when route = `Flush -> respond_unsupported reqd req.meth path ~allow:[ "DELETE" ]
+      | `POST when route = `Pull -> begin
+        (* create a pull subscription on the path, and reply with its id *)
+        Lwt.async (fun _ ->
+          Lwt.catch (fun () ->
+            expire_pull_subscriptions zenoh config.pull_ttl >>= fun () ->
+            (* each subscription holds a subscriber and its pending samples until it expires: their number is bounded *)
+            if Hashtbl.length pull_subscriptions >= config.max_pull_subscriptions then
+              Lwt.return (respond_error reqd `Service_unavailable
+                            ~headers:(Headers.of_list [ ("retry-after", string_of_int (truncate (ceil config.pull_ttl))) ])
+                            (Printf.sprintf "Too many pull subscriptions (the maximum is %d): delete the unused ones"
+                               config.max_pull_subscriptions))
+            else
+            create_pull_subscription zenoh resname >|= fun id ->
+            Log.debug (fun m -> m "[Zhttp] pull subscription %s on %s" id resname);
+            respond reqd ~status:`Created ~content_type:"application/json"
+              ~body:(Yojson.Safe.to_string (`Assoc [ ("id", `String id) ])))
+            (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+        end
+      | (`GET | `DELETE) when route = `Pull -> begin
+        (* pull the next batch of samples of a subscription (waiting __wait seconds for them, 0 by default), or delete it *)
+        match List.assoc_opt "__id" params, poll_wait ~default:0. params with
+        | None, _ -> respond_bad_request reqd "missing __id parameter (the id of the pull subscription)"
+        | _, None -> respond_bad_request reqd "invalid __wait parameter (expected a number of seconds)"
+        | Some id, Some wait ->
+          Lwt.async (fun _ ->
+            Lwt.catch (fun () ->
+              expire_pull_subscriptions zenoh config.pull_ttl >>= fun () ->
+              match Hashtbl.find_opt pull_subscriptions id with
+              | Some p when p.pull_resname = resname && req.meth = `DELETE ->
+                Hashtbl.remove pull_subscriptions id;
+                Zenoh_net.unsubscribe zenoh p.pull_sub >|= fun () -> respond reqd ~status:`No_content
+              | Some p when p.pull_resname = resname ->
+                pull_samples p wait >|= fun samples ->
+                respond reqd ~content_type:"application/json" ~body:(Zhttp_json.json_of_results ?max_value ~detail:true samples)
+              | _ -> Lwt.return (respond_error reqd `Not_found ("No pull subscription "^id^" on path: "^path)))
+              (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+        end
+      | _ when route = `Pull -> respond_unsupported reqd req.meth path ~allow:[ "GET"; "POST"; "DELETE" ]
+      | _ when route = `Upload && not (is_concrete_key resname) ->
+        respond_bad_request reqd ("an upload requires a resource name without wildcard: "^resname)
+      | `POST when route = `Upload && is_param_set params "__complete" ->
+        (* write the assembled value (the body of this request is ignored) *)
+        on_body_read_complete (Reqd.request_body reqd) (fun _ ->
+          expire_uploads config.upload_ttl;
+          match Hashtbl.find_opt uploads resname with
+          | None -> respond_error reqd `Not_found ("No upload in progress on: "^resname)
+          | Some u ->
+            let buf = Abuf.from_bytes (Buffer.to_bytes u.data) in
+            let content_type = Headers.get req.headers "content-type" in
+            let encoding = write_encoding params content_type buf in
+            (* an invalid value is kept, to be completed again with the right content type *)
+            match check_written_value ~accepted:config.accept_content_types content_type encoding buf with
+            | Error error -> respond_invalid_value reqd resname error
+            | Ok () ->
+            Hashtbl.remove uploads resname;
+            Lwt.async (fun _ ->
+              Log.debug (fun m -> m "[Zhttp] Zenoh.write put on %s of the %d uploaded bytes" resname (Buffer.length u.data));
+              Lwt.catch (fun () ->
+                write_with_retries ~retries:config.write_retries zenoh resname buf ?timestamp ~kind:zwrite_kind_put ~encoding >|= fun _ ->
+                respond reqd ~status:`No_content)
+              (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit)))
+      | `POST when route = `Upload -> begin
+        (* append a chunk at __offset (0 by default), and reply with the number of bytes received so far *)
+        match int_of_string_opt (Option.get_or_default (List.assoc_opt "__offset" params) "0") with
+        | None -> respond_bad_request reqd "invalid __offset parameter (expected a number of bytes)"
+        | Some offset ->
+          on_body_read_complete (Reqd.request_body reqd) (fun buf ->
+            expire_uploads config.upload_ttl;
+            match append_chunk ~max_total:config.max_uploads resname offset (string_of_buf buf) with
+            | Ok size ->
+              respond reqd ~content_type:"application/json" ~body:(Yojson.Safe.to_string (`Assoc [ ("received", `Int size) ]))
+            | Error (`Too_large max) ->
+              respond_error reqd `Payload_too_large
+                (Printf.sprintf "The chunk on %s would exceed the maximum size of the uploads in progress (%d bytes)" resname max)
+            | Error (`Offset size) ->
+              respond_error reqd `Conflict ~headers:(Headers.of_list [ ("upload-offset", string_of_int size) ])
+                (Printf.sprintf "The upload on %s has %d bytes: the next chunk must be at this offset" resname size))
+        end
+      | `GET when route = `Upload -> begin
+        expire_uploads config.upload_ttl;
+        match Hashtbl.find_opt uploads resname with
+        | Some u ->
+          respond reqd ~content_type:"application/json"
+            ~body:(Yojson.Safe.to_string (`Assoc [ ("received", `Int (Buffer.length u.data)) ]))
+        | None -> respond_error reqd `Not_found ("No upload in progress on: "^resname)
+        end
+      | `DELETE when route = `Upload -> Hashtbl.remove uploads resname; respond reqd ~status:`No_content
+      | _ when route = `Upload -> respond_unsupported reqd req.meth path ~allow:[ "GET"; "POST"; "DELETE" ]
+      | `POST when route = `Diff -> begin
+        (* compare the body with the stored value of the path, without writing anything *)
+        if not (is_concrete_key resname) then respond_bad_request reqd ("a diff requires a resource name without wildcard: "^resname)
+        else on_body_read_complete (Reqd.request_body reqd) (fun buf ->
+          let encoding = write_encoding params (Headers.get req.headers "content-type") buf in
+          let candidate = string_of_buf buf in
+          Lwt.async (fun _ ->
+            Lwt.catch (fun () ->
+              Zenoh_net.lquery zenoh ~consolidation:Ztypes.LatestValue resname "" >|= fun results ->
+              let stored = match results with
+                | (_, buf, (info:Ztypes.data_info)) :: _ -> Some (string_of_buf buf, Option.get_or_default info.encoding encoding_raw)
+                | [] -> None
+              in
+              respond reqd ~content_type:"application/json"
+                ~body:(Yojson.Safe.pretty_to_string (json_of_diff resname stored (candidate, encoding))))
+              (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit)))
+        end
+      | _ when route = `Diff -> respond_unsupported reqd req.meth path ~allow:[ "POST" ]
+      | `GET when route = `Tail -> begin
+        (* stream the values published on the path as lines, after the __n latest stored versions (none by default),
+           and until __count values were published (if set) *)
+        let count = match List.assoc_opt "__count" params with
+          | Some c -> (match int_of_string_opt c with Some c when c > 0 -> Ok (Some c) | _ -> Error ())
+          | None -> Ok None
+        in
+        match int_of_string_opt (Option.get_or_default (List.assoc_opt "__n" params) "0"), count with
+        | _, Error () -> respond_bad_request reqd "invalid __count parameter (expected a positive number of values)"
+        | Some n, Ok count when n >= 0 ->
+          (* the values are streamed as JSON text sequences (RFC 7464) to the clients accepting them *)
+          let json_seq = accepts_media_type req.headers "application/json-seq" in
+          let line_of =
+            if json_seq then fun result -> "\x1e"^(Zhttp_json.json_of_result result)
+            else Zhttp_json.line_of_result ~with_time:(is_param_set params "__time") ~with_key:(is_param_set params "__key") in
+          Lwt.async (fun _ ->
+            Lwt.catch (fun () ->
+              (if n = 0 then Lwt.return []
+               else Zenoh_net.lquery zenoh ~consolidation:Ztypes.KeepAll resname history_predicate) >>= fun stored ->
+              let history = tail_history n stored in
+              Log.debug (fun m -> m "[Zhttp] tail on %s" resname);
+              let body = respond_streaming reqd ~content_type:(if json_seq then "application/json-seq" else "text/plain") in
+              tail ?count zenoh resname history line_of body)
+              (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+        | _ -> respond_bad_request reqd "invalid __n parameter (expected a number of values)"
+        end
+      | _ when route = `Tail -> respond_unsupported reqd req.meth path ~allow:[ "GET" ]
+      | _ when route = `Poll && req.meth <> `GET -> respond_unsupported reqd req.meth path ~allow:[ "GET" ]
+      | `GET when route = `Poll -> begin
+        match poll_wait params with
+        | None -> respond_bad_request reqd "invalid __wait parameter (expected a number of seconds)"
+        | Some wait ->
+          Lwt.async (fun _ ->
+            Log.debug (fun m -> m "[Zhttp] poll on %s for %.1fs" resname wait);
+            Lwt.catch (fun () ->
+              poll zenoh resname wait >|= function
+              | Some sample -> respond reqd ~content_type:"application/json" ~body:(Zhttp_json.json_of_results ?max_value ~detail:true [sample])
+              | None -> respond reqd ~status:`No_content)
+            (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
+        end
+      (* the root path is not a selector: a GET on it serves the built-in index page, and the other operations are rejected *)
+      | (`GET | `HEAD) when path = "/" -> if not (respond_file path reqd) then respond_root_selector reqd
+      | _ when path = "/" -> respond_root_selector reqd
+      (* a HEAD is processed as a GET, only its body being dropped by respond. The results found are not rendered,
+         so the Content-Length is omitted unless __estimate is set: it's then the sum of the sizes of their renderings *)
+      | (`GET | `HEAD) when params_count > config.max_params ->
+        respond_bad_request reqd (Printf.sprintf "too many query parameters: %d (the maximum is %d)" params_count config.max_params)
+      | `GET | `HEAD -> begin
+        match query_shape params, List.assoc_opt "__encoding" params with
+        | Error error, _ -> respond_bad_request reqd error
+        | _, Some encoding when encoding <> "hex" -> respond_bad_request reqd ("unsupported output encoding: "^encoding^" (expected hex)")
+        | Ok json_of_results, encoding ->
+        let hex = encoding = Some "hex" in
+        (* a client accepting JSON text sequences (RFC 7464) gets one record per result *)
+        let json_of_results, content_type =
+          if accepts_media_type req.headers "application/json-seq"
+          then Zhttp_json.json_seq_of_results, "application/json-seq"
+          else json_of_results, "application/json"
+        in
+        match query_target ~default:config.default_target params with
+        | Error error -> respond_bad_request reqd error
+        | Ok (dest_storages, dest_evals) ->
+        match Headers.get req.headers "accept-charset", query_consolidation ~default:config.default_consolidation params, query_sort params,
+              time_range_predicate params predicate with
+        | Some charset, _, _, _ when not (accepts_utf8 charset) -> respond_not_acceptable reqd charset
+        | _, Error error, _, _ | _, _, Error error, _ | _, _, _, Error error -> respond_bad_request reqd error
+        | _, Ok _, Ok _, Ok predicate when String.length resname + String.length predicate > config.max_selector ->
+          respond_uri_too_long reqd (String.length resname + String.length predicate) config.max_selector
+        | _, Ok consolidation, Ok sort, Ok predicate ->
+        (* with __versions, all the replies are kept to show the version of each source (with its sequence number) *)
+        let versions = is_param_set params "__versions" in
+        let consolidation = if versions then Ztypes.KeepAll else consolidation in
+        (* a client accepting application/zip gets an archive with a file per key (containing its value), and a client
+           explicitly accepting application/octet-stream gets the bytes of the single value found *)
+        let zip = accepts_media_type req.headers "application/zip" in
+        let raw = accepts_media_type req.headers "application/octet-stream" in
+        (* the media type of the response (if it succeeds) *)
+        let media_type =
+          if zip then "application/zip" else if raw then "application/octet-stream"
+          else if is_param_set params "__single" then "application/json" else content_type in
+        (* the target and consolidation actually used (after the defaults), to help debugging the queries *)
+        let query_headers = [
+          ("x-zenoh-target", string_of_target (dest_storages, dest_evals));
+          ("x-zenoh-consolidation", string_of_consolidation consolidation) ] in
+        (* with __download, the browsers save the results as a file instead of displaying them *)
+        let query_headers = match List.assoc_opt "__download" params with
+          | Some download -> content_disposition download media_type :: query_headers
+          | None -> query_headers
+        in
+        (* with the cache enabled, a response not older than the TTL (or the max-age of the request) is reused *)
+        let cache_key = req.target^"\n"^(Option.get_or_default (Headers.get req.headers "accept") "") in
+        let cached = match config.cache_ttl, request_max_age req.headers with
+          | Some ttl, Some max_age -> find_cached_response cache_key ~max_age:(min ttl max_age)
+          | Some ttl, None -> find_cached_response cache_key ~max_age:ttl
+          | None, _ -> None
+        in
+        (* the query timeout of the request (if any), bounded by the server maximum *)
+        let query_timeout = match Headers.get req.headers "x-zenoh-query-timeout-ms" with
+          | Some ms -> (match int_of_string_opt (String.trim ms) with
+              | Some ms when ms > 0 -> Ok (Some (min ms config.query_timeout_max_ms))
+              | _ -> Error ("invalid X-Zenoh-Query-Timeout-Ms header (expected a positive number of milliseconds): "^ms))
+          | None -> Ok config.query_timeout_ms
+        in
+        match query_timeout with
+        | Error error -> respond_bad_request reqd error
+        | Ok query_timeout ->
+        match cached with
+        | _ when zip ->
+          Lwt.async (fun _ ->
+            Lwt.catch (fun () ->
+              respond_zip_query zenoh reqd ~dest_storages ~dest_evals ?max_size:config.max_response ?timeout:query_timeout
+                ~headers:(Headers.of_list query_headers) resname predicate)
+              (* the response may be started already: httpaf replies with 500 or aborts it *)
+              (fun exn -> release reqd; Reqd.report_exn reqd exn; Lwt.return_unit))
+        | Some r ->
+          let age = ("age", string_of_int (truncate (Unix.gettimeofday () -. r.cached_at))) in
+          respond reqd ~content_type:r.cached_content_type ~headers:(Headers.of_list (age :: r.cached_headers)) ~body:r.cached_body
+        | None ->
         Lwt.async (fun _ ->
           try begin
             (* TODO: manage "accept" header *)
-            Logs.debug (fun m -> m "[Zhttp] Zenoh.lquery on %s with predicate: %s" resname predicate);
-            Zenoh_net.lquery zenoh ~consolidation:KeepAll resname predicate >|= function
-            | [] -> if not (respond_file resname reqd) then respond reqd ~body:"{}"
+            Log.debug (fun m -> m "[Zhttp] Zenoh.lquery on %s with predicate: %s" resname predicate);
+            let query_start = Unix.gettimeofday () in
+            let query = Zenoh_net.lquery_with_sources zenoh ~dest_storages ~dest_evals ~consolidation resname predicate >|= fun r -> Some r in
+            (match query_timeout with
+             | Some ms -> Lwt.pick [ query; (Lwt_unix.sleep (float_of_int ms /. 1000.) >|= fun () -> None) ]
+             | None -> query) >|= function
+            | None ->
+              respond_error reqd `Gateway_timeout (Printf.sprintf "No complete reply received for %s within %dms" resname (Option.get query_timeout))
+            | Some (results, sources) ->
+            let query_end = Unix.gettimeofday () in
+            (match config.slow_query_ms with
+             | Some threshold when (query_end -. query_start) *. 1000. > float_of_int threshold ->
+               Log.warn (fun m -> m "[Zhttp] slow query (%.0fms) on %s with predicate \"%s\": %d replies (target %s, consolidation %s)"
+                            ((query_end -. query_start) *. 1000.) resname predicate (List.length results)
+                            (string_of_target (dest_storages, dest_evals)) (string_of_consolidation consolidation))
+             | _ -> ());
+            let with_meta results body =
+              if is_param_set params "__meta" && content_type = "application/json" then Zhttp_json.json_with_meta ~sources results body else body in
+            (* with __default, a GET matching nothing gets a synthetic result, rendered like the results found *)
+            let synthetic, results = match results with
+              | [] when List.mem_assoc "__default" params ->
+                true, [ Zhttp_json.default_result resname (percent_decode (List.assoc "__default" params)) ]
+              | results -> false, results
+            in
+            match results with
+            | [] when is_param_set params "__single" || raw -> respond_error reqd `Not_found ("No resource found for: "^resname)
+            | [] ->
+              if not (respond_file path reqd) then
+                respond reqd ~content_type ~headers:(Headers.of_list query_headers)
+                  ~body:(with_meta [] (json_of_results []))
             | results ->
-              Logs.debug (fun m -> m "[Zhttp] Zenoh.lquery received %d key/values" (List.length results));
-              respond reqd ~body:(json_of_results results)
+              Log.debug (fun m -> m "[Zhttp] Zenoh.lquery received %d key/values" (List.length results));
+              match decode_results params results with
+              | Error error -> respond_bad_request reqd error
+              | Ok results ->
+              match convert_results params results with
+              | Error (`Unsupported error) -> respond_unsupported_media_type reqd error
+              | Error (`Invalid error) -> respond_bad_request reqd error
+              | Ok results ->
+              match point_results params results with
+              | Error (`Bad_request error) -> respond_bad_request reqd error
+              | Error (`Not_found error) -> respond_error reqd `Not_found error
+              | Ok results ->
+                let results = match sort with Some order -> Zhttp_json.sort_results order results | None -> results in
+                let detail = versions || is_param_set params "__detail" in
+                let parse = is_param_set params "__parse" in
+                (* with __single, the only result is returned as an object (not in an array) *)
+                match is_param_set params "__single" || raw, results with
+                | true, [] -> respond_error reqd `Not_found ("No resource found for: "^resname)
+                | true, (_::_::_) ->
+                  respond_error reqd `Conflict
+                    (Printf.sprintf "%d results found for %s (a single one was expected)" (List.length results) resname)
+                | _, [ (_, buf, _) ] when raw ->
+                  respond reqd ~content_type:"application/octet-stream" ~headers:(Headers.of_list query_headers) ~body:(string_of_buf buf)
+                | single, results when req.meth = `HEAD ->
+                  let content_type = if single then "application/json" else content_type in
+                  let headers = Headers.of_list query_headers in
+                  if not (is_param_set params "__estimate") then respond reqd ~content_type ~headers ~content_length:`Unknown
+                  else begin
+                    let plain = not (is_param_set params "__meta") && not versions
+                                && List.assoc_opt "__shape" params <> Some "map" && not (List.mem_assoc "__group" params) in
+                    let size =
+                      if single then String.length (Zhttp_json.json_of_result ?max_value ~detail ~hex ~parse ~synthetic (List.hd results))
+                      else if content_type = "application/json-seq" then Zhttp_json.json_seq_size_of_results ?max_value ~detail ~hex ~parse ~synthetic results
+                      else if plain then Zhttp_json.json_size_of_results ?max_value ~detail ~hex ~parse ~synthetic results
+                      else String.length (json_of_results ?max_value ~detail ~hex ~parse ~synthetic results |> with_meta results)
+                    in
+                    match config.max_response with
+                    | Some max when size > max -> respond_too_large reqd size max
+                    | _ -> respond reqd ~content_type ~headers ~content_length:(`Length size)
+                  end
+                | single, results ->
+                let body =
+                  if single then Zhttp_json.json_of_result ?max_value ~detail ~hex ~parse ~synthetic (List.hd results)
+                  else json_of_results ?max_value ~detail ~hex ~parse ~synthetic results |> with_meta results
+                in
+                (* the time spent in the query and in the rendering of its results, in milliseconds *)
+                let timing = Printf.sprintf "query;dur=%.1f, serialize;dur=%.1f"
+                    ((query_end -. query_start) *. 1000.) ((Unix.gettimeofday () -. query_end) *. 1000.) in
+                match config.max_response with
+                | Some max when String.length body > max -> respond_too_large reqd (String.length body) max
+                | _ ->
+                  let content_type = if single then "application/json" else content_type in
+                  (* a default value isn't cached: the resource may be written to at any time *)
+                  if config.cache_ttl <> None && not synthetic then cache_response cache_key resname content_type query_headers body;
+                  respond reqd ~content_type ~headers:(Headers.of_list (("server-timing", timing) :: query_headers)) ~body
           end with
           | exn ->
             respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit
         )
         end
+      | (`PUT | `Other "PATCH" | `POST | `DELETE) when not (is_concrete_key resname) ->
+        respond_bad_request reqd ("writes require a concrete resource name, without wildcard: "^resname
+                                  ^(if req.meth = `DELETE then " (see /@/http/flush to remove a subtree)" else ""))
+      | (`PUT | `Other "PATCH") when timestamp_error <> None -> respond_bad_request reqd (Option.get timestamp_error)
+      | (`PUT | `Other "PATCH") when not (is_allowed_content_type config.accept_content_types (Headers.get req.headers "content-type")) ->
+        respond_unsupported_media_type reqd (unaccepted_content_type config.accept_content_types (Headers.get req.headers "content-type"))
       | `PUT -> begin
           try begin
             on_body_read_complete (Reqd.request_body reqd) (
               fun buf ->
+                let encoding = write_encoding params (Headers.get req.headers "content-type") buf in
+                match invalid_value encoding buf with
+                | Some error -> respond_invalid_value reqd resname error
+                | None ->
                 Lwt.async (fun _ ->
-                  let encoding = encoding_of_content_type @@ Headers.get req.headers "content-type" in
-                  Logs.debug (fun m -> m "[Zhttp] Zenoh.write put on %s %d bytes with encoding %Ld" resname (Abuf.readable_bytes buf) encoding);
-                  Zenoh_net.write zenoh resname buf ~kind:zwrite_kind_put ~encoding >|= fun _ ->
-                  respond reqd ~status:`No_content)
+                  Log.debug (fun m -> m "[Zhttp] Zenoh.write put on %s %d bytes with encoding %Ld" resname (Abuf.readable_bytes buf) encoding);
+                  Lwt.catch (fun () ->
+                    write_with_retries ~retries:config.write_retries zenoh resname buf ?timestamp ~kind:zwrite_kind_put ~encoding >|= fun _ ->
+                    respond reqd ~status:`No_content)
+                  (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
             )
           end with
           | exn ->
@@ -161,31 +1961,86 @@ let request_handler zenoh zpid (_ : Unix.sockaddr) reqd =
           try begin
             on_body_read_complete (Reqd.request_body reqd) (
               fun buf ->
+                let content_type = Headers.get req.headers "content-type" in
+                let encoding = if is_merge_patch content_type then encoding_json else write_encoding params content_type buf in
+                match invalid_value encoding buf with
+                | Some error -> respond_invalid_value reqd resname error
+                | None ->
                 Lwt.async (fun _ ->
-                  let encoding = encoding_of_content_type @@ Headers.get req.headers "content-type" in
-                  Logs.debug (fun m -> m "[Zhttp] Zenoh.write update on %s %d bytes with encoding %Ld" resname (Abuf.readable_bytes buf) encoding);
-                  Zenoh_net.write zenoh resname buf ~kind:zwrite_kind_update ~encoding >|= fun _ ->
-                  respond reqd ~status:`No_content)
+                  Lwt.catch (fun () ->
+                    if is_merge_patch content_type then begin
+                      Log.debug (fun m -> m "[Zhttp] JSON merge-patch on %s with %d bytes" resname (Abuf.readable_bytes buf));
+                      json_merge_patch zenoh resname buf >>= function
+                      | Ok merged ->
+                        let merged = Abuf.from_bytes @@ Bytes.of_string @@ Yojson.Safe.to_string merged in
+                        write_with_retries ~retries:config.write_retries zenoh resname merged ?timestamp ~kind:zwrite_kind_put ~encoding:encoding_json >|= fun _ ->
+                        respond reqd ~status:`No_content
+                      | Error error -> respond_unsupported_media_type reqd error; Lwt.return_unit
+                    end else begin
+                      Log.debug (fun m -> m "[Zhttp] Zenoh.write update on %s %d bytes with encoding %Ld" resname (Abuf.readable_bytes buf) encoding);
+                      write_with_retries ~retries:config.write_retries zenoh resname buf ?timestamp ~kind:zwrite_kind_update ~encoding >|= fun _ ->
+                      respond reqd ~status:`No_content
+                    end)
+                  (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit))
             )
           end with
           | exn ->
             respond_internal_error reqd (Printexc.to_string exn)
         end
+      | `POST -> begin
+        (* a request/reply: write the body on the resource, and reply with the first value published
+           on the __reply resource within __wait seconds *)
+        match List.assoc_opt "__reply" params, poll_wait params with
+        | None, _ -> respond_bad_request reqd "missing __reply parameter (the resource of the reply)"
+        | _, None -> respond_bad_request reqd "invalid __wait parameter (expected a number of seconds)"
+        | Some reply, Some wait ->
+          match scoped_path config.prefix (Zhttp_rewrite.rewrite config.rewrites reply) with
+          | None -> respond_forbidden reqd reply
+          (* the reply is read from its resource: it's subject to the same read rules as a GET *)
+          | Some resource when not (is_readable ~allow:config.read_allow ~deny:config.read_deny resource) ->
+            respond_forbidden reqd reply
+          | Some reply ->
+            on_body_read_complete (Reqd.request_body reqd) (fun buf ->
+              let content_type = Headers.get req.headers "content-type" in
+              let encoding = write_encoding params content_type buf in
+              match check_written_value ~accepted:config.accept_content_types content_type encoding buf with
+              | Error error -> respond_invalid_value reqd resname error
+              | Ok () ->
+              Lwt.async (fun _ ->
+                let write () =
+                  Log.debug (fun m -> m "[Zhttp] Zenoh.write request on %s, waiting for reply on %s" resname reply);
+                  write_with_retries ~retries:config.write_retries zenoh resname buf ~kind:zwrite_kind_put ~encoding
+                in
+                Lwt.catch (fun () ->
+                  poll ~before:write zenoh reply wait >|= function
+                  | Some (_, buf, (info:Ztypes.data_info)) ->
+                    respond reqd ~content_type:(content_type_of_encoding info.encoding) ~body:(string_of_buf buf)
+                  | None -> respond_gateway_timeout reqd reply wait)
+                (fun exn -> respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit)))
+        end
       | `DELETE -> begin
         Lwt.async (fun _ ->
           try begin
-            Logs.debug (fun m -> m "[Zhttp] Zenoh_net.write remove on %s" resname);
-            Zenoh_net.write zenoh resname empty_buf ~kind:zwrite_kind_remove >|= fun _ ->
-            respond reqd ~status:`No_content
+            (* with __check the existence of the resource is verified first, to return 404 if it doesn't exist *)
+            (if is_param_set params "__check" then (Zenoh_net.lquery zenoh resname "" >|= fun results -> results <> [])
+            else Lwt.return true) >>= function
+            | false -> respond_not_found reqd resname; Lwt.return_unit
+            | true ->
+              Log.debug (fun m -> m "[Zhttp] Zenoh_net.write remove on %s" resname);
+              invalidate_cached_responses resname;
+              Zenoh_net.write zenoh resname empty_buf ~kind:zwrite_kind_remove >|= fun _ ->
+              (* Zenoh_net.write is not acknowledged by the routing layer: if __ack was requested,
+                 reply 202 to tell the client that the removal was only sent *)
+              respond reqd ~status:(if is_param_set params "__ack" then `Accepted else `No_content)
           end with
           | exn ->
             respond_internal_error reqd (Printexc.to_string exn); Lwt.return_unit
         )
         end
-      | _ -> respond_unsupported reqd req.meth resname
+      | _ -> respond_unsupported reqd req.meth resname ~allow:[ "GET"; "HEAD"; "PUT"; "PATCH"; "POST"; "DELETE" ]
   end with
   | exn ->
-    Logs.err (fun m -> m "Exception %s raised:\n%s" (Printexc.to_string exn) (Printexc.get_backtrace ()));
+    Log.err (fun m -> m "Exception %s raised:\n%s" (Printexc.to_string exn) (Printexc.get_backtrace ()));
     raise exn
 
 
@@ -193,50 +2048,131 @@ let error_handler _ (_ : Unix.sockaddr) ?request:_ error start_response =
   let response_body = start_response Headers.empty in
   begin match error with
   | `Exn exn ->
-    Logs.debug (fun m -> m "[Zhttp] error_handler: %s\n%s" (Printexc.to_string exn) (Printexc.get_backtrace ()));
+    Log.debug (fun m -> m "[Zhttp] error_handler: %s\n%s" (Printexc.to_string exn) (Printexc.get_backtrace ()));
     Body.write_string response_body "INTERNAL SERVER ERROR:\n";
     Body.write_string response_body (Printexc.to_string exn);
     Body.write_string response_body "\n";
   | #Status.standard as error ->
-    Logs.debug (fun m -> m "[Zhttp] error_handler: #Status.standard \n%s" (Printexc.get_backtrace ()));
+    Log.debug (fun m -> m "[Zhttp] error_handler: #Status.standard \n%s" (Printexc.get_backtrace ()));
     Body.write_string response_body "INTERNAL SERVER ERROR:\n";
     Body.write_string response_body (Status.default_reason_phrase error)
   end;
   Body.close_writer response_body
 
-let run port =
-  let listen_address = Unix.(ADDR_INET (inet_addr_any, port)) in
+(* The number of queries (and the delay between them) of the self-test, to let the storages receive its value *)
+let selftest_attempts = 5
+let selftest_delay = 0.2
+
+(* A PUT and GET round-trip on a key reserved to the plugin under [path], that is removed afterwards:
+   returns an error if the value written can't be read back (e.g. if no storage covers [path]) *)
+let selftest zenoh zpid path =
+  let resname = path^"/@zhttp-selftest/"^zpid in
+  let value = Printf.sprintf "selftest %f" (Unix.gettimeofday ()) in
+  let rec check n =
+    Zenoh_net.lquery zenoh resname "" >>= fun results ->
+    if List.exists (fun (_, buf, _) -> string_of_buf buf = value) results then Lwt.return (Ok ())
+    else if n < selftest_attempts then Lwt_unix.sleep selftest_delay >>= fun () -> check (n+1)
+    else Lwt.return (Error (Printf.sprintf "the value written on %s was not read back (%d results)" resname (List.length results)))
+  in
+  Lwt.catch (fun () ->
+    Zenoh_net.write zenoh resname (Abuf.from_bytes (Bytes.of_string value)) ~kind:zwrite_kind_put ~encoding:encoding_string
+    >>= fun () -> Lwt.finalize (fun () -> check 1)
+      (fun () -> Zenoh_net.write zenoh resname empty_buf ~kind:zwrite_kind_remove))
+    (fun exn -> Lwt.return (Error (Printexc.to_string exn)))
+
+let run config =
+  let normalize = function Some p -> Some (normalize_prefix p) | None -> None in
+  let config = { config with prefix = normalize config.prefix; base_path = normalize config.base_path } in
+  response_headers := config.headers;
+  error_page := config.error_page;
+  publishers_max := config.publisher_cache;
+  idempotency_max := config.idempotency_cache;
+  idempotency_ttl := config.idempotency_ttl;
+  response_cache_max := config.cache_size;
+  (match config.log_level with Some level -> Logs.Src.set_level src (Some level) | None -> ());
+  if config.log_format = `Json then set_json_log_format ();
+  (match config.access_log with
+   | Some file -> access_log := Some (open_out_gen [ Open_append; Open_creat; Open_wronly ] 0o644 file, config.access_log_forwarded)
+   | None -> ());
+  (match config.tail_idle_timeout with
+   | Some idle ->
+     let rec reaper () = Lwt_unix.sleep config.tail_reaper_period >>= fun () -> reap_idle_tails idle; reaper () in
+     Lwt.async reaper
+   | None -> ());
+  (* With a read port and/or a write port, the reads (GET and HEAD) and the writes are served on distinct ports
+     (the main port serving the ones without a dedicated port). Otherwise all the requests are served on the main port. *)
+  let listeners = match config.read_port, config.write_port with
+    | None, None -> [ (config.port, `All) ]
+    | read_port, write_port ->
+      let read_port = Option.get_or_default read_port config.port and write_port = Option.get_or_default write_port config.port in
+      (* the reads and writes on the same port are served by a single listener *)
+      if read_port = write_port then [ (read_port, `All) ] else [ (read_port, `Read); (write_port, `Write) ]
+  in
+  (* The server listens before the zenoh session is opened: until then, the requests are replied with 503 *)
+  let session = ref None in
+  let request_handler methods addr reqd =
+    if !access_log <> None then clients := (reqd, addr) :: !clients;
+    let meth = (Reqd.request reqd).meth in
+    let is_read = meth = `GET || meth = `HEAD in
+    match !session, methods with
+    | _, `Read when not is_read -> respond_unsupported reqd meth (Reqd.request reqd).target ~allow:[ "GET"; "HEAD" ]
+    | _, `Write when is_read ->
+      respond_unsupported reqd meth (Reqd.request reqd).target ~allow:[ "PUT"; "PATCH"; "POST"; "DELETE" ]
+    | Some (zns, zpid), _ ->
+      (* a request failing with an exception (replied by error_handler) is no longer being processed *)
+      (try request_handler zns zpid config addr reqd with exn -> release reqd; raise exn)
+    | None, _ -> respond_unavailable reqd
+  in
+  Lwt_list.map_s (fun (port, methods) ->
+      let listen_address = Unix.(ADDR_INET (inet_addr_any, port)) in
+      Lwt_io.establish_server_with_client_socket listen_address
+        (Server.create_connection_handler ~request_handler:(request_handler methods) ~error_handler:(error_handler ()))
+      >|= fun server ->
+      Log.info (fun m -> m "[Zhttp] listening on port tcp/0.0.0.0:%d%s" port
+        (match methods with `All -> "" | `Read -> " (reads)" | `Write -> " (writes)"));
+      server)
+    listeners
+  >>= fun servers ->
   let%lwt zns = Zenoh_net.zopen "" in
   let zprops = Zenoh_net.info zns in
   let zpid = match Properties.get "peer_pid" zprops with
     | Some pid -> pid
     | None -> Uuid.make () |> Uuid.to_string
   in
-  Lwt_io.establish_server_with_client_socket listen_address 
-    (Server.create_connection_handler ~request_handler:(request_handler zns zpid) ~error_handler:(error_handler zns))
-  >|= fun _ ->
+  (match config.selftest with
+   | Some path ->
+     selftest zns zpid path >|= (function
+       | Ok () -> Log.info (fun m -> m "[Zhttp] self-test on %s passed" path); true
+       | Error error -> Log.err (fun m -> m "[Zhttp] self-test on %s failed: %s" path error); not config.selftest_abort)
+   | None -> Lwt.return true)
+  >>= function
+  | false ->
+    (* the startup is aborted: the listeners are closed (instead of replying to all the requests with 503) *)
+    Log.err (fun m -> m "[Zhttp] startup aborted after the self-test failure: the HTTP plugin doesn't serve any request");
+    Lwt_list.iter_p Lwt_io.shutdown_server servers >>= fun () -> Zenoh_net.zclose zns
+  | true ->
+  session := Some (zns, zpid);
+  Log.debug (fun m -> m "[Zhttp] zenoh session ready");
+  (* the pull subscriptions are also expired periodically, in case no other pull request is received *)
+  let rec pull_reaper () =
+    Lwt_unix.sleep config.pull_ttl >>= fun () ->
+    Lwt.catch (fun () -> expire_pull_subscriptions zns config.pull_ttl)
+      (fun exn -> Log.warn (fun m -> m "[Zhttp] expiry of the pull subscriptions failed: %s" (Printexc.to_string exn)); Lwt.return_unit)
+    >>= pull_reaper
+  in
+  Lwt.async pull_reaper;
   Zenoh_net.evaluate zns ("/@/router/" ^ zpid ^ "/plugin/http")  (fun _ _ -> 
     let data = Abuf.create ~grow:65536 1024 in 
-    let locators = Aunix.inet_addrs_up_nolo () 
-      |> List.map (fun addr -> `String (Printf.sprintf "http://%s:%d" (Unix.string_of_inet_addr addr) port)) in
+    let locators = Aunix.inet_addrs_up_nolo ()
+      |> List.map (fun addr -> List.map (fun (port, _) ->
+          `String (Printf.sprintf "http://%s:%d" (Unix.string_of_inet_addr addr) port)) listeners)
+      |> List.concat in
     let json = `Assoc [ ("locators",  `List locators); ] in
     Abuf.write_bytes (Bytes.unsafe_of_string (Yojson.Safe.to_string json)) data;
     let info = Ztypes.({srcid=None; srcsn=None; bkrid=None; bkrsn=None; ts=Some(timestamp0); encoding=Some 4L (* JSON *); kind=None}) in
     Lwt.return [("/@/router/" ^ zpid ^ "/plugin/http", data, info)]
   )
   >|= fun _ ->
-  Logs.info (fun m -> m "[Zhttp] listening on port tcp/0.0.0.0:%d" port)
-
-let port = Cmdliner.Arg.(value & opt int 8000 & info ["h"; "httpport"] ~docv:"HTTPPORT" ~doc:"Listening http port")
-
-let _ = 
-  Logs.debug (fun m -> m "[Zhttp] starting with args: %s" (Array.to_list Sys.argv |> String.concat " "));
-  match Cmdliner.Term.(eval ~argv:Sys.argv (const run $ port, Cmdliner.Term.info "zenoh-http")) with
-  | `Ok _ -> ()
-  | `Help -> exit 0
-  | `Error `Parse ->
-    Logs.err (fun m -> m "Error parsing zenoh-http options: %s" (Array.to_list Sys.argv |> String.concat " "));
-    exit 1
-  | `Error `Term -> exit 2 (* by default term eval error is written to err by Term.eval *)
-  | `Error `Exn -> exit 3 (* by default exception is caught and written to err by Term.eval *)
-  | _ -> exit 4
+  match config.prefix with
+  | Some p -> Log.info (fun m -> m "[Zhttp] all requests are scoped under prefix %s" p)
+  | None -> ()
@@ -0,0 +1,193 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+(* Entry point of the zenoh-http plugin: parse the plugin's arguments into a Zenoh_http.config and run it.
+   Applications embedding zenoh-http as a library can call Zenoh_http.run directly instead. *)
+
+open Cmdliner
+
+let default = Zenoh_http.default_config
+
+let port = Arg.(value & opt int default.port & info ["h"; "httpport"] ~docv:"HTTPPORT" ~doc:"Listening http port")
+let prefix = Arg.(value & opt (some string) default.prefix & info ["http-prefix"] ~docv:"PREFIX"
+  ~doc:"If set, prefix prepended to the path of all requests before it becomes a zenoh resource name.
+        Requests trying to escape from this prefix (e.g. via '..') are rejected with 403.")
+let write_retries = Arg.(value & opt int default.write_retries & info ["http-write-retries"] ~docv:"N"
+  ~doc:"Number of times a PUT or PATCH is retried (with exponential backoff) when the zenoh write fails with a transient error.")
+let max_value = Arg.(value & opt (some int) default.max_value & info ["http-max-value"] ~docv:"BYTES"
+  ~doc:"If set, the values returned by a GET are truncated to this size (and marked with \"truncated\": true).
+        A GET with the __full parameter returns the complete values.")
+let rewrites = Arg.(value & opt (some file) None & info ["http-rewrite"] ~docv:"FILE"
+  ~doc:"If set, file of rewrite rules (one \"<path pattern> <resource name>\" per line, e.g. \"/v1/temp/* /sensors/temperature/*\")
+        applied to the path of all requests. The paths matching no rule are unchanged.")
+let flush_token = Arg.(value & opt (some string) default.flush_token & info ["http-flush-token"] ~docv:"TOKEN"
+  ~doc:"If set, enables DELETE /@/http/flush?prefix=<selector>&confirm=<TOKEN> that removes all the resources
+        matching the selector. Without this option, or with a wrong token, the flush is rejected with 403.")
+let header =
+  let parse s = match Zenoh_http.header_of_string s with Ok h -> Ok h | Error e -> Error (`Msg e) in
+  let print fmt (name, value) = Format.fprintf fmt "%s: %s" name value in
+  Arg.conv (parse, print)
+let headers = Arg.(value & opt_all header default.headers & info ["http-header"] ~docv:"\"NAME: VALUE\""
+  ~doc:"Header added to all the responses (e.g. \"X-Content-Type-Options: nosniff\"). Can be repeated.")
+let static_dir = Arg.(value & opt (some dir) default.static_dir & info ["http-static-dir"] ~docv:"DIR"
+  ~doc:"If set, the files of this directory are served under the /ui/ path (which is then no longer mapped to zenoh resources).")
+let log_level =
+  let levels = [ ("error", Some Logs.Error); ("warning", Some Logs.Warning); ("info", Some Logs.Info); ("debug", Some Logs.Debug) ] in
+  Arg.(value & opt (enum levels) default.log_level & info ["http-log-level"] ~docv:"LEVEL"
+    ~doc:"Log level of the plugin ($(b,error), $(b,warning), $(b,info) or $(b,debug)). By default, the router's log level is used.")
+let log_format =
+  Arg.(value & opt (enum [ ("text", `Text); ("json", `Json) ]) default.log_format & info ["http-log-format"] ~docv:"FORMAT"
+    ~doc:"Format of the plugin's logs: $(b,text) (as the router's logs) or $(b,json) (one JSON object per line on stderr).")
+let accept_content_types = Arg.(value & opt (list string) default.accept_content_types & info ["http-accept-content-types"]
+  ~docv:"TYPES" ~doc:"If set, comma separated list of the only media types accepted for PUT and PATCH (e.g. \"application/json,text/plain\").
+        The writes with another (or no) Content-Type are rejected with 415.")
+let default_target =
+  let parse s = match Zenoh_http.target_of_string ~default:default.default_target s with
+    | Ok t -> Ok t | Error e -> Error (`Msg e) in
+  let print fmt target = Format.pp_print_string fmt (Zenoh_http.string_of_target target) in
+  Arg.(value & opt (conv (parse, print)) default.default_target & info ["http-default-target"] ~docv:"TARGET"
+    ~doc:"Query target used by a GET without __target parameter: \"storages:<dest>,evals:<dest>\" (or \"<dest>\" for both),
+          with <dest> one of $(b,none), $(b,best_match), $(b,all) or $(b,complete-<n>). Default: best_match.")
+let default_consolidation =
+  let parse s = match Zenoh_http.consolidation_of_string s with Ok c -> Ok c | Error e -> Error (`Msg e) in
+  let print fmt c = Format.pp_print_string fmt (Zenoh_http.string_of_consolidation c) in
+  Arg.(value & opt (conv (parse, print)) default.default_consolidation & info ["http-default-consolidation"] ~docv:"MODE"
+    ~doc:"Consolidation used by a GET without __consolidation parameter ($(b,none) or $(b,latest)). Default: none.")
+let access_log = Arg.(value & opt (some string) default.access_log & info ["http-access-log"] ~docv:"FILE"
+  ~doc:"If set, file where each request is logged as a JSON line (time, client address, method, path, status and user agent).")
+let access_log_forwarded = Arg.(value & flag & info ["http-access-log-forwarded"]
+  ~doc:"Log the client address from the X-Forwarded-For header (when present), for a plugin behind a proxy.")
+let publisher_cache = Arg.(value & opt int default.publisher_cache & info ["http-publisher-cache"] ~docv:"N"
+  ~doc:"If greater than 0, the publishers declared for the written resources are kept for the N most recently written ones,
+        so that repeated writes on a same resource reuse its declaration.")
+let max_response = Arg.(value & opt (some int) default.max_response & info ["http-max-response"] ~docv:"BYTES"
+  ~doc:"If set, a GET whose response would be bigger than this size is rejected with 413
+        (a zip archive growing beyond this size once streamed is aborted).")
+let base_path = Arg.(value & opt (some string) default.base_path & info ["http-base-path"] ~docv:"PATH"
+  ~doc:"If set, base path the plugin is mounted at behind a reverse proxy (e.g. /zenoh): it's stripped from the requests' path
+        before it's mapped to a zenoh resource name. The requests not under this path get 404.")
+let normalize_slash = Arg.(value & flag & info ["http-normalize-slash"]
+  ~doc:"Normalize the requests' path before it's mapped to a zenoh resource name: the sequences of '/' are collapsed
+        in a single '/' and the trailing '/' is removed (e.g. /a//b/ is mapped to /a/b). By default the paths are kept as is.")
+let per_client_concurrency = Arg.(value & opt (some int) default.per_client_concurrency
+  & info ["http-per-client-concurrency"] ~docv:"N"
+  ~doc:"If set, maximum number of requests processed at the same time for a same client address:
+        the requests exceeding it are rejected with 429. The polls (on /@/http/poll) are not counted.")
+let pull_ttl = Arg.(value & opt float default.pull_ttl & info ["http-pull-ttl"] ~docv:"SECONDS"
+  ~doc:"Time after which a pull subscription (created with a POST on /@/http/pull/<selector>) that is no longer pulled
+        is undeclared. Default: 60 seconds.")
+let max_pull_subscriptions = Arg.(value & opt int default.max_pull_subscriptions
+                                  & info ["http-max-pull-subscriptions"] ~docv:"N"
+  ~doc:"Maximum number of pull subscriptions (on /@/http/pull/<selector>) at the same time: the creation of another one
+        is rejected with 503 until one is deleted or expires (see --http-pull-ttl). Default: 1024.")
+let read_port = Arg.(value & opt (some int) default.read_port & info ["http-read-port"] ~docv:"PORT"
+  ~doc:"If set, port serving only the reads (GET and HEAD). The writes are then served on the --http-write-port
+        (or on the main port if not set). If the reads and the writes end up on the same port, it serves all the requests.")
+let write_port = Arg.(value & opt (some int) default.write_port & info ["http-write-port"] ~docv:"PORT"
+  ~doc:"If set, port serving only the writes (PUT, PATCH, POST and DELETE). The reads are then served on the --http-read-port
+        (or on the main port if not set).")
+let max_selector = Arg.(value & opt int default.max_selector & info ["http-max-selector-len"] ~docv:"BYTES"
+  ~doc:"Maximum length of the selector (resource name and predicate) of a GET: the longer ones are rejected with 414.
+        Default: 8192 bytes.")
+let upload_ttl = Arg.(value & opt float default.upload_ttl & info ["http-upload-ttl"] ~docv:"SECONDS"
+  ~doc:"Time after which a chunked upload (on /@/http/upload/<path>) that receives no more chunks is dropped.
+        Default: 300 seconds.")
+let max_uploads = Arg.(value & opt int default.max_uploads & info ["http-max-uploads"] ~docv:"BYTES"
+  ~doc:"Maximum total size of the chunked uploads in progress (on /@/http/upload/<path>): a chunk that would exceed it
+        is rejected with 413. Default: 64MB.")
+let idempotency_cache = Arg.(value & opt int default.idempotency_cache & info ["http-idempotency-cache"] ~docv:"N"
+  ~doc:"If greater than 0, the results of the N most recent writes with an Idempotency-Key header are kept,
+        so that a write retried with the same key is replied with the same result without writing again.
+        A write with the key of a write still being processed is rejected with 409, and a key reused for another
+        method, resource or body with 422.")
+let idempotency_ttl = Arg.(value & opt float default.idempotency_ttl & info ["http-idempotency-ttl"] ~docv:"SECONDS"
+  ~doc:"Time during which the result of a write with an Idempotency-Key header is kept. Default: 300 seconds.")
+let cache_ttl = Arg.(value & opt (some float) default.cache_ttl & info ["http-cache-ttl"] ~docv:"SECONDS"
+  ~doc:"If set, the GET responses are cached for this time (or less if the request has a Cache-Control max-age),
+        and dropped when a matching key is written through the plugin.")
+let cache_size = Arg.(value & opt int default.cache_size & info ["http-cache-size"] ~docv:"N"
+  ~doc:"Maximum number of responses in the cache enabled by --http-cache-ttl (the least recently used being dropped).
+        Default: 1000.")
+let read_allow = Arg.(value & opt (list string) default.read_allow & info ["http-read-allow"] ~docv:"SELECTORS"
+  ~doc:"If set, comma separated list of selectors (e.g. \"/public/**\"): only the reads (GET, HEAD, poll, pull, tail, diff
+        and the __reply of a POST) on a selector included in one of them are allowed, the others being rejected with 403.
+        The selectors are matched against the resource names after the --http-prefix is applied.")
+let read_deny = Arg.(value & opt (list string) default.read_deny & info ["http-read-deny"] ~docv:"SELECTORS"
+  ~doc:"If set, comma separated list of selectors (e.g. \"/internal/**\"): the reads on a selector that could match
+        a key matching one of them are rejected with 403 (even if allowed by --http-read-allow).
+        The selectors are matched against the resource names after the --http-prefix is applied.")
+let slow_query_ms = Arg.(value & opt (some int) default.slow_query_ms & info ["http-slow-query-ms"] ~docv:"MS"
+  ~doc:"If set, the GET queries taking more than this time (in milliseconds) are logged as warnings,
+        with their selector and number of replies.")
+let max_params = Arg.(value & opt int default.max_params & info ["http-max-params"] ~docv:"N"
+  ~doc:"Maximum number of query parameters (including the reserved __ ones) of a GET: the requests with more parameters
+        are rejected with 400. Default: 256.")
+let error_page = Arg.(value & opt (some file) None & info ["http-error-page"] ~docv:"FILE"
+  ~doc:"If set, HTML template of the error responses for the clients accepting HTML: its {{status}}, {{title}}
+        and {{message}} placeholders are replaced by the status code, its reason phrase and the error message.
+        The template must contain the {{message}} placeholder.")
+let query_timeout_ms = Arg.(value & opt (some int) default.query_timeout_ms & info ["http-query-timeout"] ~docv:"MS"
+  ~doc:"If set, default timeout (in milliseconds) of the queries of the GETs: the queries not completed within it
+        are answered with 504. A request can override it with a X-Zenoh-Query-Timeout-Ms header.")
+let query_timeout_max_ms = Arg.(value & opt int default.query_timeout_max_ms & info ["http-query-timeout-max"] ~docv:"MS"
+  ~doc:"Maximum query timeout (in milliseconds) a request can set with a X-Zenoh-Query-Timeout-Ms header:
+        greater values are clamped to it. Default: 60000.")
+let selftest = Arg.(value & opt (some string) default.selftest & info ["http-selftest"] ~docv:"PATH"
+  ~doc:"If set, at startup (before serving the requests), write a value on a key reserved to the plugin under PATH
+        (that a storage must cover), read it back and remove it, and log if this self-test passed or failed.")
+let selftest_abort = Arg.(value & flag & info ["http-selftest-abort"]
+  ~doc:"Abort the startup if the self-test (see --http-selftest) failed: the HTTP listeners are closed
+        and the plugin's zenoh session is closed.")
+let tail_idle_timeout = Arg.(value & opt (some float) default.tail_idle_timeout & info ["http-tail-idle-timeout"] ~docv:"SECONDS"
+  ~doc:"If set, the tails (/@/http/tail/...) that didn't write any value for more than this time are stopped
+        (and their subscriber undeclared) by a background reaper.")
+let tail_reaper_period = Arg.(value & opt float default.tail_reaper_period & info ["http-tail-reaper-period"] ~docv:"SECONDS"
+  ~doc:"Period at which the reaper of the idle tails (see --http-tail-idle-timeout) runs. Default: 60.")
+
+let config =
+  let make port prefix write_retries max_value rewrites flush_token headers static_dir log_level log_format
+      accept_content_types default_target default_consolidation access_log access_log_forwarded publisher_cache
+      max_response base_path normalize_slash per_client_concurrency pull_ttl max_pull_subscriptions read_port write_port
+      max_selector upload_ttl max_uploads idempotency_cache idempotency_ttl cache_ttl cache_size
+      read_allow read_deny slow_query_ms max_params error_page query_timeout_ms query_timeout_max_ms
+      selftest selftest_abort tail_idle_timeout tail_reaper_period =
+    let rewrites = match rewrites with Some file -> Zhttp_rewrite.rules_of_file file | None -> default.rewrites in
+    let error_page = match error_page with Some file -> Some (Zenoh_http.error_page_of_file file) | None -> default.error_page in
+    { Zenoh_http.port; prefix; write_retries; max_value; rewrites; flush_token; headers; static_dir; log_level; log_format;
+      accept_content_types; default_target; default_consolidation; access_log; access_log_forwarded; publisher_cache;
+      max_response; base_path; normalize_slash; per_client_concurrency; pull_ttl; max_pull_subscriptions;
+      read_port; write_port;
+      max_selector; upload_ttl; max_uploads; idempotency_cache; idempotency_ttl; cache_ttl; cache_size;
+      read_allow; read_deny; slow_query_ms; max_params; error_page; query_timeout_ms; query_timeout_max_ms;
+      selftest; selftest_abort; tail_idle_timeout; tail_reaper_period } in
+  Term.(const make $ port $ prefix $ write_retries $ max_value $ rewrites $ flush_token $ headers $ static_dir
+        $ log_level $ log_format $ accept_content_types $ default_target $ default_consolidation
+        $ access_log $ access_log_forwarded $ publisher_cache $ max_response $ base_path
+        $ normalize_slash $ per_client_concurrency $ pull_ttl $ max_pull_subscriptions $ read_port $ write_port
+        $ max_selector $ upload_ttl $ max_uploads $ idempotency_cache $ idempotency_ttl $ cache_ttl $ cache_size
+        $ read_allow $ read_deny $ slow_query_ms $ max_params $ error_page
+        $ query_timeout_ms $ query_timeout_max_ms $ selftest $ selftest_abort
+        $ tail_idle_timeout $ tail_reaper_period)
+
+let _ = 
+  Logs.debug (fun m -> m "[Zhttp] starting with args: %s" (Array.to_list Sys.argv |> String.concat " "));
+  match Term.(eval ~argv:Sys.argv (const Zenoh_http.run $ config, Term.info "zenoh-http")) with
+  | `Ok _ -> ()
+  | `Help -> exit 0
+  | `Error `Parse ->
+    Logs.err (fun m -> m "Error parsing zenoh-http options: %s" (Array.to_list Sys.argv |> String.concat " "));
+    exit 1
+  | `Error `Term -> exit 2 (* by default term eval error is written to err by Term.eval *)
+  | `Error `Exn -> exit 3 (* by default exception is caught and written to err by Term.eval *)
+  | _ -> exit 4
@@ -0,0 +1,243 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+(* A codec decodes the values returned by a GET into a form readable by the clients.
+   The codec to be used is selected by name with the "__decode" query parameter, and it only applies
+   to the values stored with one of the encodings it's registered for (e.g. base64 doesn't apply to JSON values). *)
+type codec = string -> (string, string) result
+
+let codecs : (string * int64, codec) Hashtbl.t = Hashtbl.create 4
+
+let register ~encodings name (codec:codec) = List.iter (fun encoding -> Hashtbl.replace codecs (name, encoding) codec) encodings
+
+let find ~encoding name = Hashtbl.find_opt codecs (name, encoding)
+
+let exists name = Hashtbl.fold (fun (name', _) _ exists -> exists || name' = name) codecs false
+
+let encoding_raw = 0x00L
+let encoding_string = 0x02L
+
+let base64_digit = function
+  | 'A'..'Z' as c -> Some (Char.code c - Char.code 'A')
+  | 'a'..'z' as c -> Some (Char.code c - Char.code 'a' + 26)
+  | '0'..'9' as c -> Some (Char.code c - Char.code '0' + 52)
+  | '+' | '-' -> Some 62
+  | '/' | '_' -> Some 63
+  | _ -> None
+
+(* Decode a base64 (or base64url) encoded value. Padding and whitespaces are ignored. *)
+let base64_decode s =
+  let out = Buffer.create (String.length s * 3 / 4) in
+  let rec decode i acc bits =
+    if i = String.length s then
+      if bits >= 6 then Error "truncated base64 value" else Ok (Buffer.contents out)
+    else match s.[i] with
+      | '=' | ' ' | '\t' | '\r' | '\n' -> decode (i+1) acc bits
+      | c -> match base64_digit c with
+        | None -> Error (Printf.sprintf "invalid base64 character at offset %d" i)
+        | Some d ->
+          let acc = (acc lsl 6) lor d and bits = bits + 6 in
+          if bits >= 8 then begin
+            Buffer.add_char out (Char.chr ((acc lsr (bits - 8)) land 0xFF));
+            decode (i+1) (acc land ((1 lsl (bits - 8)) - 1)) (bits - 8)
+          end else decode (i+1) acc bits
+  in
+  decode 0 0 0
+
+exception Inflate_error of string
+
+let length_base = [| 3; 4; 5; 6; 7; 8; 9; 10; 11; 13; 15; 17; 19; 23; 27; 31; 35; 43; 51; 59; 67; 83; 99; 115; 131; 163; 195; 227; 258 |]
+let length_extra = [| 0; 0; 0; 0; 0; 0; 0; 0; 1; 1; 1; 1; 2; 2; 2; 2; 3; 3; 3; 3; 4; 4; 4; 4; 5; 5; 5; 5; 0 |]
+let distance_base = [| 1; 2; 3; 4; 5; 7; 9; 13; 17; 25; 33; 49; 65; 97; 129; 193; 257; 385; 513; 769; 1025; 1537; 2049; 3073;
+                       4097; 6145; 8193; 12289; 16385; 24577 |]
+let distance_extra = [| 0; 0; 0; 0; 1; 1; 2; 2; 3; 3; 4; 4; 5; 5; 6; 6; 7; 7; 8; 8; 9; 9; 10; 10; 11; 11; 12; 12; 13; 13 |]
+let code_lengths_order = [| 16; 17; 18; 0; 8; 7; 9; 6; 10; 5; 11; 4; 12; 3; 13; 2; 14; 1; 15 |]
+
+(* A canonical Huffman code: the number of codes of each length (1 to 15), and the symbols by code *)
+type huffman = { counts : int array; symbols : int array }
+
+let huffman lengths =
+  let counts = Array.make 16 0 and offsets = Array.make 16 0 in
+  Array.iter (fun l -> counts.(l) <- counts.(l) + 1) lengths;
+  for l = 1 to 14 do offsets.(l + 1) <- offsets.(l) + counts.(l) done;
+  let symbols = Array.make (Array.length lengths) 0 in
+  Array.iteri (fun symbol l ->
+      if l <> 0 then begin symbols.(offsets.(l)) <- symbol; offsets.(l) <- offsets.(l) + 1 end) lengths;
+  { counts; symbols }
+
+(* Inflate the raw DEFLATE data (see RFC 1951) at [pos] in [s], into at most [max_size] bytes.
+   The decompressed data is returned with the position of the byte following the compressed data. *)
+let inflate ~max_size s pos =
+  let pos = ref pos and bit_buffer = ref 0 and bit_count = ref 0 in
+  let bits n =
+    while !bit_count < n do
+      if !pos >= String.length s then raise (Inflate_error "truncated data");
+      bit_buffer := !bit_buffer lor (Char.code s.[!pos] lsl !bit_count);
+      incr pos;
+      bit_count := !bit_count + 8
+    done;
+    let v = !bit_buffer land ((1 lsl n) - 1) in
+    bit_buffer := !bit_buffer lsr n;
+    bit_count := !bit_count - n;
+    v
+  in
+  let decode h =
+    let rec decode l code first index =
+      if l > 15 then raise (Inflate_error "invalid code") else
+      let code = code lor bits 1 in
+      let count = h.counts.(l) in
+      if code - count < first then h.symbols.(index + code - first)
+      else decode (l + 1) (code lsl 1) ((first + count) lsl 1) (index + count)
+    in
+    decode 1 0 0 0
+  in
+  let out = Buffer.create (min max_size (String.length s * 4)) in
+  let add_char c =
+    if Buffer.length out >= max_size then raise (Inflate_error (Printf.sprintf "decompressed value larger than %d bytes" max_size));
+    Buffer.add_char out c
+  in
+  let rec codes lengths distances =
+    match decode lengths with
+    | symbol when symbol < 256 -> add_char (Char.chr symbol); codes lengths distances
+    | 256 -> ()
+    | symbol ->
+      let symbol = symbol - 257 in
+      if symbol >= 29 then raise (Inflate_error "invalid length code");
+      let length = length_base.(symbol) + bits length_extra.(symbol) in
+      let symbol = decode distances in
+      if symbol >= 30 then raise (Inflate_error "invalid distance code");
+      let distance = distance_base.(symbol) + bits distance_extra.(symbol) in
+      if distance > Buffer.length out then raise (Inflate_error "distance too far back");
+      for _ = 1 to length do add_char (Buffer.nth out (Buffer.length out - distance)) done;
+      codes lengths distances
+  in
+  let stored () =
+    bit_buffer := 0; bit_count := 0;
+    if !pos + 4 > String.length s then raise (Inflate_error "truncated data");
+    let u16 i = Char.code s.[i] lor (Char.code s.[i + 1] lsl 8) in
+    let length = u16 !pos and nlength = u16 (!pos + 2) in
+    if length <> (lnot nlength) land 0xFFFF then raise (Inflate_error "invalid stored block length");
+    pos := !pos + 4;
+    if !pos + length > String.length s then raise (Inflate_error "truncated data");
+    String.iter add_char (String.sub s !pos length);
+    pos := !pos + length
+  in
+  let fixed = lazy (
+    huffman (Array.init 288 (fun i -> if i < 144 then 8 else if i < 256 then 9 else if i < 280 then 7 else 8)),
+    huffman (Array.make 30 5)) in
+  let dynamic () =
+    let nlengths = bits 5 + 257 in
+    let ndistances = bits 5 + 1 in
+    let ncodes = bits 4 + 4 in
+    if nlengths > 286 || ndistances > 30 then raise (Inflate_error "too many length or distance codes");
+    let code_lengths = Array.make 19 0 in
+    for i = 0 to ncodes - 1 do code_lengths.(code_lengths_order.(i)) <- bits 3 done;
+    let code_lengths = huffman code_lengths in
+    let lengths = Array.make (nlengths + ndistances) 0 in
+    let rec read i =
+      if i < nlengths + ndistances then
+        match decode code_lengths with
+        | symbol when symbol < 16 -> lengths.(i) <- symbol; read (i + 1)
+        | symbol ->
+          let length, repeat = match symbol with
+            | 16 when i = 0 -> raise (Inflate_error "repeated length without previous length")
+            | 16 -> lengths.(i - 1), 3 + bits 2
+            | 17 -> 0, 3 + bits 3
+            | _ -> 0, 11 + bits 7
+          in
+          if i + repeat > nlengths + ndistances then raise (Inflate_error "too many lengths");
+          Array.fill lengths i repeat length;
+          read (i + repeat)
+    in
+    read 0;
+    if lengths.(256) = 0 then raise (Inflate_error "no end of block code");
+    codes (huffman (Array.sub lengths 0 nlengths)) (huffman (Array.sub lengths nlengths ndistances))
+  in
+  let rec blocks () =
+    let last = bits 1 = 1 in
+    (match bits 2 with
+     | 0 -> stored ()
+     | 1 -> let lengths, distances = Lazy.force fixed in codes lengths distances
+     | 2 -> dynamic ()
+     | _ -> raise (Inflate_error "invalid block type"));
+    if not last then blocks ()
+  in
+  blocks ();
+  (Buffer.contents out, !pos)
+
+(* The maximum size of a value decompressed by the gzip codec *)
+let gzip_max_size = 64 * 1024 * 1024
+
+(* Decompress a gzip value (see RFC 1952), checking its CRC and size *)
+let gzip_decode s =
+  let len = String.length s in
+  let u8 i = if i < len then Char.code s.[i] else raise (Inflate_error "truncated header") in
+  let u16 i = u8 i lor (u8 (i + 1) lsl 8) in
+  let u32 i = u16 i lor (u16 (i + 2) lsl 16) in
+  let rec skip_string i = if u8 i = 0 then i + 1 else skip_string (i + 1) in
+  try
+    if u8 0 <> 0x1f || u8 1 <> 0x8b then Error "not a gzip value"
+    else if u8 2 <> 8 then Error "unsupported gzip compression method"
+    else
+      let flags = u8 3 in
+      let pos = 10 in
+      let pos = if flags land 0x04 <> 0 then pos + 2 + u16 pos else pos in
+      let pos = if flags land 0x08 <> 0 then skip_string pos else pos in
+      let pos = if flags land 0x10 <> 0 then skip_string pos else pos in
+      let pos = if flags land 0x02 <> 0 then pos + 2 else pos in
+      let data, pos = inflate ~max_size:gzip_max_size s pos in
+      if pos + 8 > len then Error "truncated gzip value"
+      else if u32 pos <> Zhttp_zip.crc32 data then Error "invalid gzip CRC"
+      else if u32 (pos + 4) <> String.length data land 0xFFFFFFFF then Error "invalid gzip size"
+      else Ok data
+  with
+  | Inflate_error e -> Error e
+  | Invalid_argument _ -> Error "invalid compressed data"
+
+let () =
+  register ~encodings:[ encoding_raw; encoding_string ] "base64" base64_decode;
+  register ~encodings:[ encoding_raw ] "gzip" gzip_decode
+
+(* A converter transcodes a value from an encoding to a content type (e.g. properties to JSON).
+   The conversion to be applied is selected with the "__convert" query parameter, by target content type. *)
+type converter = string -> (string, string) result
+
+let converters : (int64 * string, converter) Hashtbl.t = Hashtbl.create 4
+
+let register_converter ~from ~target (converter:converter) = Hashtbl.replace converters (from, target) converter
+
+let find_converter ~from ~target = Hashtbl.find_opt converters (from, target)
+
+(* Convert "key1=value1;key2=value2" properties to a JSON object (with string values) *)
+let properties_to_json s =
+  let property p = match String.index_opt p '=' with
+    | Some i -> (String.trim (String.sub p 0 i), `String (String.sub p (i+1) (String.length p - i - 1)))
+    | None -> (String.trim p, `String "")
+  in
+  let props = String.split_on_char ';' s |> List.filter (fun p -> String.trim p <> "") |> List.map property in
+  Ok (Yojson.Safe.to_string (`Assoc props))
+
+(* Convert an integer or float value to a JSON number *)
+let number_to_json s =
+  let s = String.trim s in
+  match Int64.of_string_opt s, float_of_string_opt s with
+  | Some n, _ -> Ok (Int64.to_string n)
+  | None, Some f when (match classify_float f with FP_nan | FP_infinite -> false | _ -> true) ->
+    Ok (Yojson.Safe.to_string (`Float f))
+  | _ -> Error ("not a number: "^s)
+
+let () =
+  register_converter ~from:0x03L (* PROPERTIES *) ~target:"application/json" properties_to_json;
+  register_converter ~from:0x06L (* INT *) ~target:"application/json" number_to_json;
+  register_converter ~from:0x07L (* FLOAT *) ~target:"application/json" number_to_json
@@ -0,0 +1,370 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+open Apero
+open Ztypes
+
+let encoding_json = 0x04L
+
+let timestamp0 = Timestamp.create 
+  (Option.get @@ Uuid.of_string "00000000-0000-0000-0000-000000000000")
+  (Option.get @@ Timestamp.Time.of_string "0")
+
+(* A JSON string literal, with all the required characters escaped *)
+let json_string s = Yojson.Safe.to_string (`String s)
+
+let json_of_time (info:data_info) = match info.ts with
+  | None -> "None"
+  | Some ts when ts=timestamp0 -> "None"
+  | Some ts -> Timestamp.Time.to_rfc3339 @@ Timestamp.get_time ts
+
+let json_of_value value (info:data_info) =
+  match info.encoding with
+  | Some e when e=encoding_json -> value
+  | _ -> json_string value
+
+(* Truncate [s] to at most [max] bytes, without cutting a UTF-8 encoded character *)
+let utf8_truncate max s =
+  if String.length s <= max then s
+  else
+    let rec char_start i = if i > 0 && Char.code s.[i] land 0xC0 = 0x80 then char_start (i-1) else i in
+    String.sub s 0 (char_start max)
+
+let string_of_encoding = function
+  | 0x00L -> "raw"
+  | 0x02L -> "string"
+  | 0x03L -> "properties"
+  | 0x04L -> "json"
+  | 0x05L -> "sql"
+  | 0x06L -> "int"
+  | 0x07L -> "float"
+  | e -> Int64.to_string e
+
+let string_of_kind = function
+  | 0L -> "put"
+  | 1L -> "update"
+  | 2L -> "remove"
+  | k -> Int64.to_string k
+
+(* The source id and sequence number of a sample (when known), to detect reorderings *)
+let hex_of_string s =
+  let b = Buffer.create (2 * String.length s) in
+  String.iter (fun c -> Buffer.add_string b (Printf.sprintf "%02x" (Char.code c))) s;
+  Buffer.contents b
+
+(* The values are decoded as strings, except for the raw (or unknown) encodings *)
+let is_text_encoding e = e <> 0x00L && e <= 0x07L
+
+let is_text (info:data_info) =
+  match info.encoding with
+  | Some e -> is_text_encoding e
+  | None -> false
+
+(* The JSON parsed from a text value (other than a JSON one, already rendered as is), or None if it's not valid JSON *)
+let parsed_json value (info:data_info) =
+  if not (is_text info) || info.encoding = Some encoding_json then None
+  else try Some (Yojson.Safe.to_string (Yojson.Safe.from_string value)) with Yojson.Json_error _ -> None
+
+(* Render a result at the end of [b].
+   If [hex] is set, the non-text values are rendered as hexadecimal strings (with an "hex" encoding).
+   If [parse] is set, the text values that are valid JSON are also rendered parsed, in a "value_json" field.
+   If [synthetic] is set, the result is marked as synthetic (i.e. not stored, such as the default value of a GET). *)
+let add_result ?max_value ?(detail=false) ?(with_key=true) ?(hex=false) ?(parse=false) ?(synthetic=false) b (resname, buf, (info:data_info)) =
+  let value = Abuf.read_bytes (Abuf.readable_bytes buf) buf |> Bytes.to_string in
+  let value, info, hexa = if hex && not (is_text info) then hex_of_string value, { info with encoding = None }, true
+    else value, info, false in
+  let json_value, truncated = match max_value with
+    (* a removal has no value *)
+    | _ when info.kind = Some 2L -> "null", false
+    (* a truncated JSON is no longer valid: render it as a string *)
+    | Some max when String.length value > max -> json_string (utf8_truncate max value), true
+    | _ -> json_of_value value info, false
+  in
+  let first = ref true in
+  let field name json =
+    if not !first then Buffer.add_string b ",\n  ";
+    first := false;
+    Buffer.add_char b '"'; Buffer.add_string b name; Buffer.add_string b "\": "; Buffer.add_string b json
+  in
+  let optional name to_string = function
+    | Some v -> field name (json_string @@ to_string v)
+    | None -> ()
+  in
+  (* the fields are always in this order (clients may hash the responses) *)
+  Buffer.add_string b "{ ";
+  if with_key then field "key" (json_string resname);
+  field "value" json_value;
+  if parse && not truncated && not hexa then
+    (match parsed_json value info with Some json -> field "value_json" json | None -> ());
+  if hexa then field "encoding" "\"hex\"" else optional "encoding" string_of_encoding info.encoding;
+  optional "kind" string_of_kind info.kind;
+  field "time" (json_string @@ json_of_time info);
+  if truncated then field "truncated" "true";
+  if detail then begin
+    optional "source_id" Abuf.hexdump info.srcid;
+    (match info.srcsn with Some sn -> field "source_sn" (Int64.to_string sn) | None -> ())
+  end;
+  if synthetic then field "synthetic" "true";
+  Buffer.add_string b " }"
+
+let json_of_result ?max_value ?detail ?with_key ?hex ?parse ?synthetic result =
+  let b = Buffer.create 256 in
+  add_result ?max_value ?detail ?with_key ?hex ?parse ?synthetic b result;
+  Buffer.contents b
+
+(* The buffer the results are rendered in (each one with add_result, without intermediate strings), reused from
+   a rendering to the other. It can't be re-entered: a rendering doesn't yield to Lwt (it's a plain function,
+   called from a callback without any bind inside), so no other request can use the buffer before the rendering
+   is copied out, and the functions given to render never call render themselves.
+   It's shrunk after a rendering bigger than [render_buffer_max]. *)
+let render_buffer = Buffer.create 4096
+let render_buffer_max = 1024 * 1024
+
+let render f =
+  let b = render_buffer in
+  Buffer.clear b;
+  f b;
+  let rendered = Buffer.contents b in
+  if Buffer.length b > render_buffer_max then Buffer.reset b;
+  rendered
+
+(* Add the [items] to [b], separated by [sep] *)
+let add_separated b sep add items =
+  List.iteri (fun i item -> if i > 0 then Buffer.add_string b sep; add item) items
+
+(* Render the results of a query as a JSON text sequence (see RFC 7464): each result is a record prefixed
+   with a record separator (0x1E) and ended by a line feed, so that a client can parse them incrementally *)
+let json_seq_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  render (fun b ->
+      List.iter (fun result ->
+          Buffer.add_char b '\x1e';
+          add_result ?max_value ?detail ?hex ?parse ?synthetic b result;
+          Buffer.add_char b '\n') results)
+
+(* Render the results of a query as a JSON array (an empty array if there is no result).
+   Each result is an object with the fields "key", "value", "encoding", "kind" and "time", in this order
+   ("encoding" and "kind" are omitted when unknown). The value of a removal (kind "remove") is null.
+   If [max_value] is set, the values bigger than [max_value] bytes are truncated and marked as such.
+   If [detail] is set, the source id and sequence number of each result are added.
+   If [hex] is set, the non-text values are rendered as hexadecimal strings.
+   If [parse] is set, a "value_json" field follows the "value" of the text values that are valid JSON. *)
+let json_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  match results with
+  | [] -> "[]"
+  | results ->
+    render (fun b ->
+        Buffer.add_string b "[\n";
+        add_separated b ",\n" (fun result -> add_result ?max_value ?detail ?hex ?parse ?synthetic b result) results;
+        Buffer.add_string b "\n]")
+
+(* The size of the rendering of each result (in the render buffer, one after the other), followed by [separator] *)
+let size_of_results ?max_value ?detail ?hex ?parse ?synthetic ~separator results =
+  let b = render_buffer in
+  let size = List.fold_left (fun size result ->
+      Buffer.clear b;
+      add_result ?max_value ?detail ?hex ?parse ?synthetic b result;
+      size + Buffer.length b + separator) 0 results
+  in
+  if Buffer.length b > render_buffer_max then Buffer.reset b;
+  size
+
+(* The sizes of the renderings of json_of_results and json_seq_of_results, computed without building them *)
+let json_size_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  2 + size_of_results ?max_value ?detail ?hex ?parse ?synthetic ~separator:2 results
+
+let json_seq_size_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  size_of_results ?max_value ?detail ?hex ?parse ?synthetic ~separator:2 results
+
+(* Group the results by [group_of] (e.g. their key): the groups are in the order of their first result,
+   and the results of each group in their original order *)
+let group_results group_of results =
+  let groups = Hashtbl.create 16 in
+  let names = List.fold_left (fun names result ->
+      let name = group_of result in
+      match Hashtbl.find_opt groups name with
+      | Some results -> Hashtbl.replace groups name (result::results); names
+      | None -> Hashtbl.add groups name [ result ]; name::names
+    ) [] results
+  in
+  List.rev_map (fun name -> (name, List.rev (Hashtbl.find groups name))) names
+
+(* Render the results of a query as a JSON object mapping each key to its result (without the "key" field).
+   The keys appear in the order of their first result. A key with several results is mapped to an array. *)
+let json_map_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  let add_key b (key, results) =
+    let add_result result = add_result ?max_value ?detail ?hex ?parse ?synthetic ~with_key:false b result in
+    Buffer.add_string b (json_string key);
+    match results with
+    | [ result ] -> Buffer.add_string b ": "; add_result result
+    | results -> Buffer.add_string b ": [ "; add_separated b ", " add_result results; Buffer.add_string b " ]"
+  in
+  match group_results (fun (key, _, _) -> key) results with
+  | [] -> "{}"
+  | groups ->
+    render (fun b -> Buffer.add_string b "{\n"; add_separated b ",\n" (add_key b) groups; Buffer.add_string b "\n}")
+
+let source_of (_, _, (info:data_info)) = match info.srcid with Some id -> Abuf.hexdump id | None -> "unknown"
+
+(* Render the results of a query as a JSON object mapping each source id (as in "source_id") to the array
+   of its results. The results without source id are grouped under "unknown". *)
+let json_by_source_of_results ?max_value ?detail ?hex ?parse ?synthetic (results : (string * Abuf.t * data_info) list) =
+  let add_source b (source, results) =
+    Buffer.add_string b (json_string source);
+    Buffer.add_string b ": [\n";
+    add_separated b ",\n" (fun result -> add_result ?max_value ?detail ?hex ?parse ?synthetic b result) results;
+    Buffer.add_string b "\n]"
+  in
+  match group_results source_of results with
+  | [] -> "{}"
+  | groups ->
+    render (fun b -> Buffer.add_string b "{\n"; add_separated b ",\n" (add_source b) groups; Buffer.add_string b "\n}")
+
+(* The default value returned by a GET matching nothing (with __default): a string result, to be rendered as
+   synthetic like the results found *)
+let default_result resname value =
+  (resname, Abuf.from_bytes (Bytes.of_string value), { empty_data_info with encoding = Some 0x02L (* STRING *) })
+
+let json_of_default resname value = json_of_results ~synthetic:true [ default_result resname value ]
+
+(* Wrap the rendered [results] in an envelope with their count and the number of [sources] (storages and evals)
+   that replied to the query: { "sources": n, "count": n, "results": [...] } *)
+let json_with_meta ~sources (results : (string * Abuf.t * data_info) list) rendered =
+  Printf.sprintf "{ \"sources\": %d,\n  \"count\": %d,\n  \"results\": %s }" sources (List.length results) rendered
+
+(* Parse a sort order, as specified by the "__sort" query parameter *)
+let sort_order_of_string = function
+  | "key" -> Ok `Key
+  | "time" -> Ok `Time
+  | "-time" -> Ok `Time_desc
+  | order -> Error ("unknown sort order: "^order)
+
+let compare_time (info:data_info) (info':data_info) =
+  match info.ts, info'.ts with
+  | Some ts, Some ts' -> Timestamp.compare ts ts'
+  | None, Some _ -> -1
+  | Some _, None -> 1
+  | None, None -> 0
+
+(* Sort the results of a query by key, or by time (the results without timestamp first).
+   Results with the same time are sorted by key. *)
+let sort_results order (results : (string * Abuf.t * data_info) list) =
+  let by_key (k, _, _) (k', _, _) = String.compare k k' in
+  let by_time ~desc (k, _, i) (k', _, i') =
+    match (if desc then compare_time i' i else compare_time i i') with
+    | 0 -> String.compare k k'
+    | c -> c
+  in
+  match order with
+  | `Key -> List.stable_sort by_key results
+  | `Time -> List.stable_sort (by_time ~desc:false) results
+  | `Time_desc -> List.stable_sort (by_time ~desc:true) results
+
+(* Render a result as a line (for /@/http/tail): its value (as hexadecimal if it's not text) with the line breaks
+   and backslashes escaped, optionally prefixed with its time and its key (separated by spaces) *)
+let line_of_result ?(with_time=false) ?(with_key=false) (resname, buf, (info:data_info)) =
+  let value = Abuf.read_bytes (Abuf.readable_bytes buf) buf |> Bytes.to_string in
+  let value = if is_text info then value else hex_of_string value in
+  let line = Buffer.create (String.length value + 64) in
+  if with_time then (Buffer.add_string line (json_of_time info); Buffer.add_char line ' ');
+  if with_key then (Buffer.add_string line resname; Buffer.add_char line ' ');
+  String.iter (function
+      | '\n' -> Buffer.add_string line "\\n"
+      | '\r' -> Buffer.add_string line "\\r"
+      | '\\' -> Buffer.add_string line "\\\\"
+      | c -> Buffer.add_char line c) value;
+  Buffer.contents line
+
+(* Resolve a JSON pointer (see RFC 6901) in a JSON value. None is returned if the pointer doesn't resolve. *)
+let json_pointer pointer (json:Yojson.Safe.t) =
+  let unescape token = Str.global_replace (Str.regexp_string "~0") "~" (Str.global_replace (Str.regexp_string "~1") "/" token) in
+  let rec resolve json = function
+    | [] -> Some json
+    | token :: tokens ->
+      match json with
+      | `Assoc fields -> (match List.assoc_opt token fields with Some v -> resolve v tokens | None -> None)
+      | `List values -> begin
+        match int_of_string_opt token with
+        | Some i when i >= 0 && i < List.length values && (token = "0" || token.[0] <> '0') -> resolve (List.nth values i) tokens
+        | _ -> None
+        end
+      | _ -> None
+  in
+  match String.split_on_char '/' pointer with
+  | [ "" ] -> Some json
+  | "" :: tokens -> resolve json (List.map unescape tokens)
+  | _ -> None
+
+(* Apply a JSON merge-patch to a target JSON value (see RFC 7386) *)
+let rec merge_patch (target:Yojson.Safe.t) (patch:Yojson.Safe.t) : Yojson.Safe.t =
+  match patch with
+  | `Assoc patch_fields ->
+    let target_fields = match target with `Assoc l -> l | _ -> [] in
+    `Assoc (List.fold_left (fun fields (name, value) ->
+        match value with
+        | `Null -> List.remove_assoc name fields
+        | value when List.mem_assoc name fields ->
+          List.map (fun (n, v) -> if n = name then (n, merge_patch v value) else (n, v)) fields
+        | value -> fields @ [ (name, merge_patch `Null value) ]
+      ) target_fields patch_fields)
+  | patch -> patch
+
+(* The structural difference from a JSON value to another one, as a list of RFC 6902 like operations
+   ("add", "remove" or "replace" with a JSON pointer to the changed value). The objects are compared field by field,
+   the other values (including the arrays) as a whole. *)
+let json_diff (current:Yojson.Safe.t) (candidate:Yojson.Safe.t) =
+  let escape token = Str.global_replace (Str.regexp_string "/") "~1" (Str.global_replace (Str.regexp_string "~") "~0" token) in
+  let op name path value =
+    `Assoc ((("op", `String name) :: ("path", `String path) :: (match value with Some v -> [ ("value", v) ] | None -> [])))
+  in
+  let rec diff path current candidate =
+    match current, candidate with
+    | `Assoc current_fields, `Assoc candidate_fields ->
+      let removed = List.filter (fun (name, _) -> not (List.mem_assoc name candidate_fields)) current_fields
+        |> List.map (fun (name, _) -> op "remove" (path^"/"^(escape name)) None) in
+      let changed = List.map (fun (name, value) ->
+          let field_path = path^"/"^(escape name) in
+          match List.assoc_opt name current_fields with
+          | Some current -> diff field_path current value
+          | None -> [ op "add" field_path (Some value) ]) candidate_fields in
+      removed @ List.concat changed
+    | current, candidate when current = candidate -> []
+    | _, candidate -> [ op "replace" path (Some candidate) ]
+  in
+  diff "" current candidate
+
+(* The maximum size (number of lines of a text times the number of lines of the other) of a line diff,
+   its memory being proportional to it *)
+let line_diff_max_size = 1_000_000
+
+(* The difference from a text to another one, line by line (with a longest common subsequence):
+   the unchanged lines are prefixed with "  ", the removed ones with "- " and the added ones with "+ ".
+   None is returned if the texts are too large to be compared (see [line_diff_max_size]). *)
+let line_diff ?(max_size=line_diff_max_size) current candidate =
+  let a = Array.of_list (String.split_on_char '\n' current) and b = Array.of_list (String.split_on_char '\n' candidate) in
+  let n = Array.length a and m = Array.length b in
+  if (n+1) * (m+1) > max_size then None else begin
+    (* lcs.(i).(j) is the length of the longest common subsequence of a[i..] and b[j..] *)
+    let lcs = Array.make_matrix (n+1) (m+1) 0 in
+    for i = n - 1 downto 0 do
+      for j = m - 1 downto 0 do
+        lcs.(i).(j) <- if a.(i) = b.(j) then lcs.(i+1).(j+1) + 1 else max lcs.(i+1).(j) lcs.(i).(j+1)
+      done
+    done;
+    let rec lines acc i j =
+      if i < n && j < m && a.(i) = b.(j) then lines (("  "^a.(i)) :: acc) (i+1) (j+1)
+      else if i < n && (j = m || lcs.(i+1).(j) >= lcs.(i).(j+1)) then lines (("- "^a.(i)) :: acc) (i+1) j
+      else if j < m then lines (("+ "^b.(j)) :: acc) i (j+1)
+      else List.rev acc
+    in
+    Some (lines [] 0 0)
+  end
@@ -0,0 +1,71 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+(* A rewrite rule maps the public paths matching a pattern to zenoh resource names.
+   In both the pattern and the target, a "*" segment stands for any single segment, and a final "**" segment
+   for any non-empty sequence of segments. The segments captured by the pattern's wildcards replace,
+   in order, the target's wildcards. E.g. "/v1/temp/*" -> "/sensors/temperature/*" *)
+type rule = { pattern : string list; target : string list }
+
+let rule pattern target = { pattern = String.split_on_char '/' pattern; target = String.split_on_char '/' target }
+
+(* Load the rules from [file]: one "<pattern> <target>" per line.
+   Empty lines and lines starting with '#' are ignored. *)
+let rules_of_file file =
+  let ic = open_in file in
+  let rec read_rules rules =
+    match input_line ic with
+    | exception End_of_file -> List.rev rules
+    | line ->
+      match String.trim line with
+      | "" -> read_rules rules
+      | line when line.[0] = '#' -> read_rules rules
+      | line ->
+        match Str.split (Str.regexp "[ \t]+") line with
+        | [ pattern ; target ] -> read_rules (rule pattern target :: rules)
+        | _ -> failwith (Printf.sprintf "malformed rewrite rule in %s: %s" file line)
+  in
+  let rules = try read_rules [] with exn -> close_in ic; raise exn in
+  close_in ic;
+  rules
+
+(* Match the segments of a path against the segments of a pattern, returning the segments captured by the wildcards *)
+let rec match_segments pattern segments captures =
+  match pattern, segments with
+  | [], [] -> Some (List.rev captures)
+  | [ "**" ], (_::_ as rest) -> Some (List.rev (String.concat "/" rest :: captures))
+  | "*"::pattern, segment::segments -> match_segments pattern segments (segment::captures)
+  | p::pattern, segment::segments when p = segment -> match_segments pattern segments captures
+  | _ -> None
+
+let substitute target captures =
+  let _, segments = List.fold_left (fun (captures, segments) segment ->
+      match segment, captures with
+      | ("*" | "**"), c::captures -> (captures, c::segments)
+      | segment, captures -> (captures, segment::segments)
+    ) (captures, []) target
+  in
+  String.concat "/" (List.rev segments)
+
+(* Rewrite [path] with the first matching rule. A path matching none of the rules is returned unchanged. *)
+let rewrite rules path =
+  let segments = String.split_on_char '/' path in
+  let rec first = function
+    | [] -> path
+    | r::rules ->
+      match match_segments r.pattern segments [] with
+      | Some captures -> substitute r.target captures
+      | None -> first rules
+  in
+  first rules
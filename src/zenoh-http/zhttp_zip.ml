@@ -0,0 +1,117 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+(* A writer of zip archives with stored (i.e. not compressed) entries, written one after the other:
+   each [entry] returns the bytes of an entry as soon as it's added, and [finish] the central directory.
+   Zip64 is not supported: an entry that would make the archive exceed 65535 entries or 4GB is refused. *)
+
+let crc_table = lazy (Array.init 256 (fun n ->
+    let rec loop c k = if k = 0 then c else loop (if c land 1 = 1 then 0xEDB88320 lxor (c lsr 1) else c lsr 1) (k-1) in
+    loop n 8))
+
+let crc32 s =
+  let table = Lazy.force crc_table in
+  let crc = ref 0xFFFFFFFF in
+  String.iter (fun c -> crc := table.((!crc lxor Char.code c) land 0xFF) lxor (!crc lsr 8)) s;
+  !crc lxor 0xFFFFFFFF
+
+(* The name of the entry of a key: its path without the leading '/' and without the "." and ".." segments,
+   the characters invalid in the archive paths being replaced with '_' *)
+let entry_name key =
+  let sanitize = String.map (fun c -> match c with
+      | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' -> '_'
+      | c when Char.code c < 0x20 || Char.code c = 0x7f -> '_'
+      | c -> c)
+  in
+  match String.split_on_char '/' key |> List.filter (fun s -> s <> "" && s <> "." && s <> "..") with
+  | [] -> "_"
+  | segments -> String.concat "/" (List.map sanitize segments)
+
+type t = {
+  max_size : int;
+  mutable offset : int;
+  mutable count : int;
+  mutable directory_size : int;
+  mutable entries : (string * int * int * int) list;  (* name, crc, size and offset of each entry (in reverse order) *)
+  names : (string, unit) Hashtbl.t;  (* the names of the entries, to keep them unique *)
+}
+
+(* The limits of the zip format without Zip64 *)
+let max_entries = 0xFFFF
+let max_archive_size = 0xFFFFFFFF
+let max_name_length = 0xFFFF
+
+let local_header_size = 30
+let directory_header_size = 46
+let end_record_size = 22
+
+(* A writer of an archive of at most [max_size] bytes (and at most 4GB) *)
+let create ?(max_size=max_archive_size) () =
+  { max_size = min max_size max_archive_size; offset = 0; count = 0; directory_size = 0; entries = [];
+    names = Hashtbl.create 64 }
+
+(* The size of the archive of [zip] if it was finished now *)
+let archive_size zip = zip.offset + zip.directory_size + end_record_size
+
+let add_u16 b n = Buffer.add_char b (Char.chr (n land 0xFF)); Buffer.add_char b (Char.chr ((n lsr 8) land 0xFF))
+let add_u32 b n = add_u16 b (n land 0xFFFF); add_u16 b ((n lsr 16) land 0xFFFF)
+
+(* The MS-DOS date of the entries (1980-01-01) and their flags (general purpose bit 11: UTF-8 names) *)
+let dos_date = 0x21
+let flags = 0x0800
+
+(* The name of the entry of [key] in [zip]: distinct keys may have the same entry name (e.g. "/a:b" and "/a_b"),
+   in which case a "~N" suffix is added to the name of the later ones *)
+let unique_name zip key =
+  let name = entry_name key in
+  let rec unique n = let candidate = Printf.sprintf "%s~%d" name n in if Hashtbl.mem zip.names candidate then unique (n+1) else candidate in
+  if Hashtbl.mem zip.names name then unique 1 else name
+
+(* The bytes of the entry of [key] with [data], or an error if it exceeds the limits of the archive *)
+let entry zip key data =
+  let name = unique_name zip key in
+  let size = String.length data in
+  let entry_size = local_header_size + String.length name + size in
+  if zip.count >= max_entries then Error (Printf.sprintf "more than %d entries" max_entries)
+  else if String.length name > max_name_length then Error ("entry name too long for "^key)
+  else if archive_size zip + entry_size + directory_header_size + String.length name > zip.max_size then
+    Error (Printf.sprintf "archive larger than %d bytes" zip.max_size)
+  else
+  let crc = crc32 data in
+  let b = Buffer.create entry_size in
+  add_u32 b 0x04034b50; add_u16 b 20; add_u16 b flags; add_u16 b 0 (* stored *);
+  add_u16 b 0; add_u16 b dos_date; add_u32 b crc; add_u32 b size; add_u32 b size;
+  add_u16 b (String.length name); add_u16 b 0;
+  Buffer.add_string b name; Buffer.add_string b data;
+  zip.entries <- (name, crc, size, zip.offset) :: zip.entries;
+  Hashtbl.replace zip.names name ();
+  zip.offset <- zip.offset + entry_size;
+  zip.count <- zip.count + 1;
+  zip.directory_size <- zip.directory_size + directory_header_size + String.length name;
+  Ok (Buffer.contents b)
+
+let finish zip =
+  let b = Buffer.create 1024 in
+  let entries = List.rev zip.entries in
+  List.iter (fun (name, crc, size, offset) ->
+      add_u32 b 0x02014b50; add_u16 b 20; add_u16 b 20; add_u16 b flags; add_u16 b 0;
+      add_u16 b 0; add_u16 b dos_date; add_u32 b crc; add_u32 b size; add_u32 b size;
+      add_u16 b (String.length name); add_u16 b 0; add_u16 b 0; add_u16 b 0; add_u16 b 0; add_u32 b 0;
+      add_u32 b offset; Buffer.add_string b name)
+    entries;
+  let directory_size = Buffer.length b in
+  add_u32 b 0x06054b50; add_u16 b 0; add_u16 b 0;
+  add_u16 b (List.length entries); add_u16 b (List.length entries);
+  add_u32 b directory_size; add_u32 b zip.offset; add_u16 b 0;
+  Buffer.contents b
@@ -608,11 +608,14 @@ let squery z ?(dest_storages=Best_match) ?(dest_evals=Best_match) resname predic
   stream
 
 module RepliesMap = Map.Make(String)
-type lquery_context = {resolver: (string * Abuf.t * data_info) list Lwt.u; mutable map: (Abuf.t * data_info) list RepliesMap.t}
+type lquery_context = {
+  resolver: ((string * Abuf.t * data_info) list * int) Lwt.u;
+  mutable map: (Abuf.t * data_info) list RepliesMap.t;
+  mutable sources: int}
 
-let lquery z ?(dest_storages=Best_match) ?(dest_evals=Best_match) ?(consolidation=LatestValue) resname predicate =
+let lquery_with_sources z ?(dest_storages=Best_match) ?(dest_evals=Best_match) ?(consolidation=LatestValue) resname predicate =
   let promise,resolver = Lwt.wait () in 
-  let ctx = {resolver; map = RepliesMap.empty} in
+  let ctx = {resolver; map = RepliesMap.empty; sources = 0} in
   let add_reply resname data info =
     match RepliesMap.find_opt resname ctx.map , consolidation with
     | Some replies , LatestValue ->
@@ -629,18 +632,21 @@ let lquery z ?(dest_storages=Best_match) ?(dest_evals=Best_match) ?(consolidatio
     | StorageData {stoid=_; rsn=_; resname; data; info}
     | EvalData {stoid=_; rsn=_; resname; data; info} -> add_reply resname data info; Lwt.return_unit
     | StorageFinal {stoid=_;rsn=_}
-    | EvalFinal {stoid=_;rsn=_} -> Lwt.return_unit
+    | EvalFinal {stoid=_;rsn=_} -> ctx.sources <- ctx.sources + 1; Lwt.return_unit
     | ReplyFinal ->
       let result = RepliesMap.fold (fun resname replies result ->
           (List.map (fun (buf, info) -> (resname, buf, info)) replies ) @ result
         ) ctx.map []
       in
-      Lwt.wakeup_later ctx.resolver result;
+      Lwt.wakeup_later ctx.resolver (result, ctx.sources);
       Lwt.return_unit
   in
   let _ = (query z resname predicate reply_handler ~dest_storages ~dest_evals) in 
   promise
 
+let lquery z ?dest_storages ?dest_evals ?consolidation resname predicate =
+  Lwt.map fst (lquery_with_sources z ?dest_storages ?dest_evals ?consolidation resname predicate)
+
 
 let unstore z (sto:storage) = 
   let%lwt state = Guard.acquire z.state in
@@ -79,6 +79,11 @@ val lquery : t -> ?dest_storages:Ztypes.query_dest -> ?dest_evals:Ztypes.query_d
    The consolidation strategy is specified via the [consolidation] parameter.
  *)
 
+val lquery_with_sources : t -> ?dest_storages:Ztypes.query_dest -> ?dest_evals:Ztypes.query_dest -> ?consolidation:replies_consolidation -> string -> string -> ((string * Abuf.t * data_info) list * int) Lwt.t
+(* [lquery_with_sources] is like [lquery], but also returns the number of storages and evals that replied
+   (i.e. the number of StorageFinal and EvalFinal replies).
+ *)
+
 val unstore : t -> storage -> unit Lwt.t
 
 val unevaluate : t -> eval -> unit Lwt.t
\ No newline at end of file
@@ -35,7 +35,8 @@ export PATH=${BUILDDIR}/example/throughput/:$PATH
 export PATH=${BUILDDIR}/example/roundtrip/:$PATH
 export PATH=${ZENOHDIR}/test/common/:$PATH
 
-export ZENOH_HTTP=${BUILDDIR}/src/zenoh-http/zenoh-http-plugin.cmxs
+export ZENOH_HTTP=${BUILDDIR}/src/zenoh-http/zenoh-plugin-http.cmxs
+export ZENOH_STORAGES=${BUILDDIR}/src/zenoh-storages/zenoh-plugin-storages.cmxs
 
 source proc_mgr.sh
 source graph_tools.sh
@@ -15,7 +15,8 @@ cd "$(dirname $0)"
 source ../common/init.sh
 
 ./test_c2b/run_all.sh && \
-./test_bro/run_all.sh
+./test_bro/run_all.sh && \
+./test_http/run_all.sh
 
 if [ $? -eq 0 ]
 then
@@ -0,0 +1,33 @@
+#
+# Copyright (c) 2017, 2020 ADLINK Technology Inc.
+#
+# This program and the accompanying materials are made available under the
+# terms of the Eclipse Public License 2.0 which is available at
+# http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+# which is available at https://www.apache.org/licenses/LICENSE-2.0.
+#
+# SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+#
+# Contributors:
+#   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+#
+cd "$(dirname $0)"
+
+echo "====== START http tests"
+
+./test_http_1/test_http_1.sh && \
+./test_http_2/test_http_2.sh && \
+./test_http_3/test_http_3.sh
+
+if [ $? -eq 0 ]
+then
+    echo "[OK]"
+    echo "====== END http tests"
+    echo ""
+    exit 0
+else
+    echo "[ERROR]"
+    echo "====== END http tests"
+    echo ""
+    exit -1
+fi
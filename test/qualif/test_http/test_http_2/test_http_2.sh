@@ -0,0 +1,55 @@
+#
+# Copyright (c) 2017, 2020 ADLINK Technology Inc.
+#
+# This program and the accompanying materials are made available under the
+# terms of the Eclipse Public License 2.0 which is available at
+# http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+# which is available at https://www.apache.org/licenses/LICENSE-2.0.
+#
+# SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+#
+# Contributors:
+#   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+#
+cd "$(dirname $0)"
+
+export ZENOD_VERBOSITY=debug
+
+basename=`basename $0`
+filename="${basename%.*}"
+outdir=${filename}_`date +"%y-%m-%d_%H-%M"`
+mkdir $outdir
+
+echo "-------- START test $filename"
+
+printf "run %-20s > %s\n" "zenohd http" "$outdir/zenohd.log"
+zenohd.exe -P "$ZENOH_HTTP" > $outdir/zenohd.log 2>&1 &
+zenohdpid=$!
+
+sleep 2
+
+printf "run %-20s > %s\n" "curl poll" "$outdir/curl_poll.log"
+curl -s -i "http://127.0.0.1:8000/@/http/poll/test/http/res2?__wait=5" > $outdir/curl_poll.log 2>&1 &
+curlpollpid=$!
+
+sleep 1
+
+printf "run %-20s > %s\n" "curl put" "$outdir/curl_put.log"
+curl -s -i -X PUT -H "Content-Type: text/plain" -d "MSG" http://127.0.0.1:8000/test/http/res2 > $outdir/curl_put.log 2>&1
+
+wait $curlpollpid
+
+kill -9 $zenohdpid
+
+if [ `cat $outdir/curl_poll.log | grep '"value": "MSG"' | wc -l` -gt 0 ]
+then 
+  echo "[OK]"
+  echo "-------- END test $filename"
+  echo ""
+  exit 0
+else
+  echo "[ERROR] poll didn't receive MSG"
+  echo "-------- END test $filename"
+  echo ""
+  exit -1
+fi
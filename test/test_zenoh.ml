@@ -25,4 +25,9 @@ let () =
   Printexc.record_backtrace true;
   Alcotest.run "Zenoh Test" [
     "test_zenoh_common", Test_zenoh_common.all_tests;
+    "test_zhttp_json", Test_zhttp_json.all_tests;
+    "test_zhttp_rewrite", Test_zhttp_rewrite.all_tests;
+    "test_zhttp_codec", Test_zhttp_codec.all_tests;
+    "test_zhttp_zip", Test_zhttp_zip.all_tests;
+    "test_zenoh_http", Test_zenoh_http.all_tests;
   ]
@@ -0,0 +1,151 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+open Apero
+
+let test_concrete_keys () =
+  Alcotest.(check bool) "concrete" true (Zenoh_http.is_concrete_key "/a/b/c");
+  Alcotest.(check bool) "not empty" false (Zenoh_http.is_concrete_key "")
+
+let test_pattern_keys () =
+  Alcotest.(check bool) "*" false (Zenoh_http.is_concrete_key "/a/*/c");
+  Alcotest.(check bool) "**" false (Zenoh_http.is_concrete_key "/a/**");
+  Alcotest.(check bool) "partial *" false (Zenoh_http.is_concrete_key "/a/b*")
+
+let test_normalize_slash () =
+  Alcotest.(check string) "unchanged" "/a/b" (Zenoh_http.normalize_slash "/a/b");
+  Alcotest.(check string) "trailing" "/a/b" (Zenoh_http.normalize_slash "/a/b/");
+  Alcotest.(check string) "collapsed" "/a/b/c" (Zenoh_http.normalize_slash "//a/b//c//");
+  Alcotest.(check string) "root" "/" (Zenoh_http.normalize_slash "//")
+
+let test_key_matches () =
+  Alcotest.(check bool) "same key" true (Zenoh_http.key_matches "/a/b" "/a/b");
+  Alcotest.(check bool) "*" true (Zenoh_http.key_matches "/a/*/c" "/a/b/c");
+  Alcotest.(check bool) "* within a segment" true (Zenoh_http.key_matches "/a/b*" "/a/bc");
+  Alcotest.(check bool) "* is a single segment" false (Zenoh_http.key_matches "/a/*" "/a/b/c");
+  Alcotest.(check bool) "**" true (Zenoh_http.key_matches "/a/**" "/a/b/c");
+  Alcotest.(check bool) "other key" false (Zenoh_http.key_matches "/a/b" "/a/c")
+
+let test_selectors_intersect () =
+  Alcotest.(check bool) "**" true (Zenoh_http.selectors_intersect "/internal/**" "/**");
+  Alcotest.(check bool) "*" true (Zenoh_http.selectors_intersect "/a/*/c" "/a/b/*");
+  Alcotest.(check bool) "disjoint" false (Zenoh_http.selectors_intersect "/internal/**" "/public/*");
+  Alcotest.(check bool) "* is a single segment" false (Zenoh_http.selectors_intersect "/a/*" "/a/b/c")
+
+let test_selector_includes () =
+  Alcotest.(check bool) "key" true (Zenoh_http.selector_includes "/public/**" "/public/a/b");
+  Alcotest.(check bool) "sub selector" true (Zenoh_http.selector_includes "/public/**" "/public/*/b");
+  Alcotest.(check bool) "wider selector" false (Zenoh_http.selector_includes "/public/**" "/**");
+  Alcotest.(check bool) "** not in *" false (Zenoh_http.selector_includes "/a/*" "/a/**")
+
+let test_is_readable () =
+  let readable = Zenoh_http.is_readable ~allow:[ "/public/**" ] ~deny:[ "/public/secret/**" ] in
+  Alcotest.(check bool) "allowed" true (readable "/public/a");
+  Alcotest.(check bool) "denied" false (readable "/public/secret/a");
+  Alcotest.(check bool) "overlapping deny" false (readable "/public/**");
+  Alcotest.(check bool) "not allowed" false (readable "/other/a")
+
+let test_count_params () =
+  Alcotest.(check int) "none" 0 (Zenoh_http.count_params "");
+  Alcotest.(check int) "reserved and others" 3 (Zenoh_http.count_params "a=1&__shape=map&b");
+  Alcotest.(check int) "properties not counted" 1 (Zenoh_http.count_params "a=1(x=1&y=2)#frag&z")
+
+let test_content_disposition () =
+  let filename download content_type = snd (Zenoh_http.content_disposition download content_type) in
+  Alcotest.(check string) "name" "attachment; filename=\"results.json\"" (filename "results.json" "application/json");
+  Alcotest.(check string) "default name" "attachment; filename=\"results.json-seq\"" (filename "true" "application/json-seq");
+  Alcotest.(check string) "zip name" "attachment; filename=\"results.zip\"" (filename "" "application/zip");
+  Alcotest.(check string) "decoded name" "attachment; filename=\"my data.json\"" (filename "my%20data.json" "application/json");
+  Alcotest.(check string) "sanitized name" "attachment; filename=\"_etc_pass_wd\"" (filename "/etc/pass\"wd" "application/json")
+
+let test_static_file () =
+  let dir = Filename.temp_file "zhttp" "static" in
+  Sys.remove dir; Unix.mkdir dir 0o755;
+  List.iter (fun name -> let oc = open_out (Filename.concat dir name) in output_string oc name; close_out oc)
+    [ "index.html"; "my file.css" ];
+  let found path = match Zenoh_http.static_file dir path with Ok file -> Some file | Error _ -> None in
+  Alcotest.(check (option string)) "index.html" (Some (Filename.concat dir "index.html")) (found "/");
+  Alcotest.(check (option string)) "file" (Some (Filename.concat dir "index.html")) (found "/index.html");
+  Alcotest.(check (option string)) "decoded file" (Some (Filename.concat dir "my file.css")) (found "/my%20file.css");
+  Alcotest.(check bool) "not found" true (Zenoh_http.static_file dir "/other.css" = Error `Not_found);
+  Alcotest.(check bool) "encoded escape" true (Zenoh_http.static_file dir "/..%2Fetc%2Fpasswd" = Error `Forbidden);
+  Alcotest.(check bool) "escape" true (Zenoh_http.static_file dir "/../etc/passwd" = Error `Forbidden)
+
+let test_time_range_predicate () =
+  let predicate params = match Zenoh_http.time_range_predicate params "" with Ok p -> p | Error e -> Alcotest.fail e in
+  Alcotest.(check string) "none" "" (predicate []);
+  Alcotest.(check string) "from" "(starttime=2020-01-01T00:00:00Z)" (predicate [ ("__from", "2020-01-01T00:00:00Z") ]);
+  Alcotest.(check string) "encoded offset" "(stoptime=2020-01-01T00:00:00+01:00)"
+    (predicate [ ("__to", "2020-01-01T00%3A00%3A00%2B01%3A00") ]);
+  Alcotest.(check bool) "invalid" true
+    (match Zenoh_http.time_range_predicate [ ("__from", "yesterday") ] "" with Error _ -> true | Ok _ -> false)
+
+let test_constant_time_equal () =
+  Alcotest.(check bool) "equal" true (Zenoh_http.constant_time_equal "secret" "secret");
+  Alcotest.(check bool) "different" false (Zenoh_http.constant_time_equal "secret" "secreT");
+  Alcotest.(check bool) "different lengths" false (Zenoh_http.constant_time_equal "secret" "secret!");
+  Alcotest.(check bool) "empty" true (Zenoh_http.constant_time_equal "" "")
+
+let test_tail_history () =
+  let timed key time =
+    let ts = Ztypes.Timestamp.create
+        (Option.get @@ Uuid.of_string "00000000-0000-0000-0000-000000000001")
+        (Option.get @@ Ztypes.Timestamp.Time.of_string time) in
+    (key, Abuf.from_bytes (Bytes.of_string time), { Ztypes.empty_data_info with ts = Some ts })
+  in
+  let values history = List.map (fun (_, buf, _) -> Bytes.to_string (Abuf.read_bytes (Abuf.readable_bytes buf) buf)) history in
+  let written = [ timed "/a" "30"; timed "/a" "10"; timed "/a" "20" ] in
+  Alcotest.(check (list string)) "3 versions" [ "10"; "20"; "30" ] (values (Zenoh_http.tail_history 3 written));
+  Alcotest.(check (list string)) "latest versions" [ "20"; "30" ] (values (Zenoh_http.tail_history 2 written));
+  Alcotest.(check (list string)) "more than stored" [ "10"; "20"; "30" ] (values (Zenoh_http.tail_history 10 written));
+  Alcotest.(check (list string)) "from several storages" [ "10"; "20"; "30" ]
+    (values (Zenoh_http.tail_history 3 (written @ [ timed "/a" "30"; timed "/a" "10" ])))
+
+let test_invalid_value () =
+  let invalid encoding value = Zenoh_http.invalid_value encoding (Abuf.from_bytes (Bytes.of_string value)) in
+  Alcotest.(check bool) "raw" true (invalid 0x00L "\xff" = None);
+  Alcotest.(check bool) "string" true (invalid 0x02L "hello" = None);
+  Alcotest.(check bool) "invalid UTF-8" true (invalid 0x02L "\xff" = Some `Invalid_utf8);
+  Alcotest.(check bool) "JSON" true (invalid 0x04L "{ \"a\": [1, 2] }" = None);
+  Alcotest.(check bool) "invalid JSON" true (match invalid 0x04L "{ \"a\": " with Some (`Invalid_json _) -> true | _ -> false)
+
+let test_body_digest () =
+  let digest = Zenoh_http.body_digest in
+  Alcotest.(check bool) "same body" true (digest ~lines:false "a=1" = digest ~lines:false "a=1");
+  Alcotest.(check bool) "other body" false (digest ~lines:false "a=1" = digest ~lines:false "a=2");
+  Alcotest.(check bool) "lines" true
+    (digest ~lines:true "{}\n[]\n" = Zenoh_http.(add_line_digest (add_line_digest empty_body_digest "{}") "[]"));
+  Alcotest.(check bool) "without final line feed" true (digest ~lines:true "{}\n[]" = digest ~lines:true "{}\n[]\n");
+  Alcotest.(check bool) "empty line" false (digest ~lines:true "{}\n\n[]" = digest ~lines:true "{}\n[]");
+  Alcotest.(check bool) "other request" false
+    (Zenoh_http.idempotency_fingerprint "PUT /a" (digest ~lines:false "x")
+     = Zenoh_http.idempotency_fingerprint "POST /a" (digest ~lines:false "x"))
+
+let all_tests = [
+  "is_concrete_key with concrete keys", `Quick, test_concrete_keys;
+  "is_concrete_key with patterns", `Quick, test_pattern_keys;
+  "normalize_slash", `Quick, test_normalize_slash;
+  "key_matches", `Quick, test_key_matches;
+  "selectors_intersect", `Quick, test_selectors_intersect;
+  "selector_includes", `Quick, test_selector_includes;
+  "is_readable", `Quick, test_is_readable;
+  "count_params", `Quick, test_count_params;
+  "content_disposition", `Quick, test_content_disposition;
+  "static_file", `Quick, test_static_file;
+  "time_range_predicate", `Quick, test_time_range_predicate;
+  "constant_time_equal", `Quick, test_constant_time_equal;
+  "tail_history", `Quick, test_tail_history;
+  "invalid_value", `Quick, test_invalid_value;
+  "body_digest", `Quick, test_body_digest;
+]
@@ -0,0 +1,86 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+let decode ?(encoding=0x00L) name s = match Zhttp_codec.find ~encoding name with
+  | Some codec -> codec s
+  | None -> Alcotest.fail ("no codec "^name)
+
+let check_ok msg expected result = match result with
+  | Ok value -> Alcotest.(check string) msg expected value
+  | Error e -> Alcotest.fail (msg^": "^e)
+
+let is_error = function Error _ -> true | Ok _ -> false
+
+let test_base64 () =
+  check_ok "padded" "hello" (decode "base64" "aGVsbG8=");
+  check_ok "unpadded" "hello" (decode "base64" "aGVsbG8");
+  check_ok "url alphabet" "\xfb\xff" (decode "base64" "-_8=");
+  check_ok "empty" "" (decode "base64" "")
+
+let test_base64_invalid () =
+  Alcotest.(check bool) "invalid character" true (is_error (decode "base64" "aGV*bG8="));
+  Alcotest.(check bool) "truncated" true (is_error (decode "base64" "aGVsb"))
+
+let test_gzip () =
+  check_ok "fixed codes" "hello"
+    (decode "gzip" "\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\xcd\xc9\xc9\x07\x00\x86\xa6\x10\x36\x05\x00\x00\x00");
+  check_ok "repeated strings" (String.concat "" (Array.to_list (Array.make 20 "zenoh ")))
+    (decode "gzip" ("\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xab\x4a\xcd\xcb\xcf\x50\xa8\xa2\x3b\x09\x00\xc6\x42"
+                    ^"\xbb\x09\x78\x00\x00\x00"));
+  check_ok "dynamic codes" "bacaabaaabacaadaacdbdbaabbcaabadbbbdabcd"
+    (decode "gzip" ("\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x1d\x88\xc1\x11\x00\x30\x0c\x40\x66\x25\xf6\x9f\xa1\x69"
+                    ^"\x1e\xee\x20\x03\xf2\x59\x09\x26\xdb\xd6\xfb\xa9\xe1\xf4\x00\xa3\x43\x80\x5e\x28\x00\x00\x00"));
+  check_ok "stored" "hi"
+    (decode "gzip" "\x1f\x8b\x08\x00\x00\x00\x00\x00\x04\x03\x01\x02\x00\xfd\xff\x68\x69\xac\x2a\x93\xd8\x02\x00\x00\x00")
+
+let test_gzip_invalid () =
+  Alcotest.(check bool) "not gzip" true (is_error (decode "gzip" "hello"));
+  Alcotest.(check bool) "truncated" true
+    (is_error (decode "gzip" "\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\xcd"));
+  Alcotest.(check bool) "invalid CRC" true
+    (is_error (decode "gzip" "\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\xcd\xc9\xc9\x07\x00\x00\xa6\x10\x36\x05\x00\x00\x00"))
+
+let test_codec_encodings () =
+  Alcotest.(check bool) "base64 on a string" true (Zhttp_codec.find ~encoding:0x02L "base64" <> None);
+  Alcotest.(check bool) "base64 on a JSON value" true (Zhttp_codec.find ~encoding:0x04L "base64" = None);
+  Alcotest.(check bool) "gzip on a string" true (Zhttp_codec.find ~encoding:0x02L "gzip" = None);
+  Alcotest.(check bool) "known codec" true (Zhttp_codec.exists "base64")
+
+let test_unknown_codec () =
+  Alcotest.(check bool) "unknown codec" false (Zhttp_codec.exists "rot13")
+
+let convert from value = match Zhttp_codec.find_converter ~from ~target:"application/json" with
+  | Some converter -> converter value
+  | None -> Alcotest.fail (Printf.sprintf "no converter from %Ld" from)
+
+let test_convert_properties () =
+  check_ok "properties" "{\"a\":\"1\",\"b\":\"x=y\"}" (convert 0x03L "a=1;b=x=y");
+  check_ok "no properties" "{}" (convert 0x03L "")
+
+let test_convert_numbers () =
+  check_ok "int" "42" (convert 0x06L " 42 ");
+  check_ok "float" "1.5" (convert 0x07L "1.5");
+  Alcotest.(check bool) "not a number" true (is_error (convert 0x06L "abc"));
+  Alcotest.(check bool) "no raw converter" true (Zhttp_codec.find_converter ~from:0x00L ~target:"application/json" = None)
+
+let all_tests = [
+  "base64 codec", `Quick, test_base64;
+  "base64 codec with invalid values", `Quick, test_base64_invalid;
+  "gzip codec", `Quick, test_gzip;
+  "gzip codec with invalid values", `Quick, test_gzip_invalid;
+  "codecs by encoding", `Quick, test_codec_encodings;
+  "unknown codec", `Quick, test_unknown_codec;
+  "properties to JSON converter", `Quick, test_convert_properties;
+  "numbers to JSON converter", `Quick, test_convert_numbers;
+]
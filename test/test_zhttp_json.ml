@@ -0,0 +1,262 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+open Apero
+
+let result ?encoding key value =
+  (key, Abuf.from_bytes (Bytes.of_string value), { Ztypes.empty_data_info with encoding })
+
+let parse_array s =
+  match Yojson.Safe.from_string s with
+  | `List l -> l
+  | _ -> Alcotest.fail ("not a JSON array: "^s)
+
+let test_no_result () =
+  Alcotest.(check string) "empty array" "[]" @@ Zhttp_json.json_of_results []
+
+let test_one_result () =
+  let json = Zhttp_json.json_of_results [ result "/a/b" "hello" ] in
+  match parse_array json with
+  | [ `Assoc fields ] ->
+    Alcotest.(check bool) "key" true (List.assoc "key" fields = `String "/a/b");
+    Alcotest.(check bool) "value" true (List.assoc "value" fields = `String "hello")
+  | _ -> Alcotest.fail ("expected a single object in: "^json)
+
+let test_fields_order () =
+  let info = { Ztypes.empty_data_info with encoding = Some Zhttp_json.encoding_json; kind = Some 0L } in
+  let json = Zhttp_json.json_of_results [ ("/a/b", Abuf.from_bytes (Bytes.of_string "1"), info) ] in
+  match parse_array json with
+  | [ `Assoc fields ] ->
+    Alcotest.(check (list string)) "fields order" [ "key"; "value"; "encoding"; "kind"; "time" ] (List.map fst fields)
+  | _ -> Alcotest.fail ("expected a single object in: "^json)
+
+let test_removal () =
+  let info = { Ztypes.empty_data_info with kind = Some 2L } in
+  let json = Zhttp_json.json_of_results [ ("/a/b", Abuf.create 0, info) ] in
+  match parse_array json with
+  | [ `Assoc fields ] ->
+    Alcotest.(check bool) "null value" true (List.assoc "value" fields = `Null);
+    Alcotest.(check bool) "remove kind" true (List.assoc "kind" fields = `String "remove")
+  | _ -> Alcotest.fail ("expected a single object in: "^json)
+
+let test_default () =
+  match parse_array (Zhttp_json.json_of_default "/a/b" "none yet") with
+  | [ `Assoc fields ] ->
+    Alcotest.(check bool) "value" true (List.assoc "value" fields = `String "none yet");
+    Alcotest.(check bool) "synthetic" true (List.assoc "synthetic" fields = `Bool true)
+  | _ -> Alcotest.fail "expected a single object"
+
+let test_default_shapes () =
+  let default = Zhttp_json.default_result "/a/b" "none yet" in
+  (match Yojson.Safe.from_string (Zhttp_json.json_of_result ~synthetic:true default) with
+   | `Assoc fields -> Alcotest.(check bool) "single synthetic" true (List.assoc "synthetic" fields = `Bool true)
+   | _ -> Alcotest.fail "expected an object");
+  (match Yojson.Safe.from_string (Zhttp_json.json_map_of_results ~synthetic:true [ default ]) with
+   | `Assoc [ ("/a/b", `Assoc fields) ] -> Alcotest.(check bool) "map synthetic" true (List.assoc "synthetic" fields = `Bool true)
+   | _ -> Alcotest.fail "expected a map with a single key");
+  match parse_array (Zhttp_json.json_of_results [ default ]) with
+  | [ `Assoc fields ] -> Alcotest.(check bool) "not synthetic by default" false (List.mem_assoc "synthetic" fields)
+  | _ -> Alcotest.fail "expected a single object"
+
+let test_many_results () =
+  let json = Zhttp_json.json_of_results [
+    result "/a/1" "say \"hi\"";
+    result "/a/2" ~encoding:Zhttp_json.encoding_json "{\"x\": 1}";
+    result "/a/3" "line1\nline2";
+  ] in
+  Alcotest.(check int) "3 objects" 3 (List.length @@ parse_array json)
+
+let test_detail () =
+  let buf = Abuf.from_bytes (Bytes.of_string "hello") in
+  let info = { Ztypes.empty_data_info with srcid = Some (Abuf.from_bytes (Bytes.of_string "\x01\x02")); srcsn = Some 42L } in
+  let fields json = match parse_array json with
+    | [ `Assoc fields ] -> fields
+    | _ -> Alcotest.fail ("expected a single object in: "^json)
+  in
+  let without = fields @@ Zhttp_json.json_of_results [ ("/a/b", Abuf.duplicate buf, info) ] in
+  Alcotest.(check bool) "no source_sn by default" false (List.mem_assoc "source_sn" without);
+  let with_detail = fields @@ Zhttp_json.json_of_results ~detail:true [ ("/a/b", buf, info) ] in
+  Alcotest.(check bool) "source_id" true (List.mem_assoc "source_id" with_detail);
+  Alcotest.(check bool) "source_sn" true (List.assoc "source_sn" with_detail = `Int 42)
+
+let test_map_shape () =
+  let json = Zhttp_json.json_map_of_results [ result "/a/1" "x"; result "/a/2" "y"; result "/a/1" "z" ] in
+  match Yojson.Safe.from_string json with
+  | `Assoc [ ("/a/1", `List [ `Assoc first; `Assoc second ]); ("/a/2", `Assoc single) ] ->
+    Alcotest.(check bool) "first /a/1 value" true (List.assoc "value" first = `String "x");
+    Alcotest.(check bool) "second /a/1 value" true (List.assoc "value" second = `String "z");
+    Alcotest.(check bool) "no key field" false (List.mem_assoc "key" single)
+  | _ -> Alcotest.fail ("unexpected map shape: "^json)
+
+let test_by_source () =
+  let from source key value =
+    let info = { Ztypes.empty_data_info with srcid = Some (Abuf.from_bytes (Bytes.of_string source)) } in
+    (key, Abuf.from_bytes (Bytes.of_string value), info)
+  in
+  let json = Zhttp_json.json_by_source_of_results [ from "\x01" "/a/1" "x"; result "/a/1" "y"; from "\x01" "/a/2" "z" ] in
+  match Yojson.Safe.from_string json with
+  | `Assoc [ (_, `List [ `Assoc first; `Assoc second ]); ("unknown", `List [ `Assoc unknown ]) ] ->
+    Alcotest.(check bool) "first value" true (List.assoc "value" first = `String "x");
+    Alcotest.(check bool) "second value" true (List.assoc "value" second = `String "z");
+    Alcotest.(check bool) "without source" true (List.assoc "value" unknown = `String "y")
+  | _ -> Alcotest.fail ("unexpected grouping by source: "^json)
+
+let test_meta () =
+  let from source key value =
+    (key, Abuf.from_bytes (Bytes.of_string value), { Ztypes.empty_data_info with srcid = Some (Abuf.from_bytes (Bytes.of_string source)) })
+  in
+  let results = [ from "\x01" "/a/1" "x"; from "\x02" "/a/1" "y"; from "\x01" "/a/2" "z" ] in
+  match Yojson.Safe.from_string (Zhttp_json.json_with_meta ~sources:2 results (Zhttp_json.json_of_results results)) with
+  | `Assoc [ ("sources", `Int 2); ("count", `Int 3); ("results", `List l) ] ->
+    Alcotest.(check int) "results" 3 (List.length l)
+  | json -> Alcotest.fail ("unexpected envelope: "^(Yojson.Safe.to_string json))
+
+let test_hex () =
+  let json = Zhttp_json.json_of_results ~hex:true [
+    result "/a/raw" "\x00\xffA";
+    result "/a/text" ~encoding:0x02L "hello";
+  ] in
+  match parse_array json with
+  | [ `Assoc raw; `Assoc text ] ->
+    Alcotest.(check bool) "raw value as hex" true (List.assoc "value" raw = `String "00ff41");
+    Alcotest.(check bool) "hex encoding" true (List.assoc "encoding" raw = `String "hex");
+    Alcotest.(check bool) "text value unchanged" true (List.assoc "value" text = `String "hello")
+  | _ -> Alcotest.fail ("expected two objects in: "^json)
+
+let test_parse () =
+  let json = Zhttp_json.json_of_results ~parse:true [
+    result "/a/json" ~encoding:0x02L "{\"x\": [1, 2]}";
+    result "/a/text" ~encoding:0x02L "{not json";
+  ] in
+  match parse_array json with
+  | [ `Assoc valid; `Assoc invalid ] ->
+    Alcotest.(check bool) "raw value" true (List.assoc "value" valid = `String "{\"x\": [1, 2]}");
+    Alcotest.(check bool) "parsed value" true (List.assoc "value_json" valid = `Assoc [ ("x", `List [ `Int 1; `Int 2 ]) ]);
+    Alcotest.(check bool) "no parsed value" false (List.mem_assoc "value_json" invalid)
+  | _ -> Alcotest.fail ("expected two objects in: "^json)
+
+let test_json_seq () =
+  let seq = Zhttp_json.json_seq_of_results [ result "/a/1" "x"; result "/a/2" "y" ] in
+  match String.split_on_char '\x1e' seq with
+  | [ ""; first; second ] ->
+    Alcotest.(check bool) "ends with a line feed" true (first.[String.length first - 1] = '\n' && second.[String.length second - 1] = '\n');
+    Alcotest.(check bool) "valid record" true (match Yojson.Safe.from_string first with `Assoc _ -> true | _ -> false)
+  | _ -> Alcotest.fail ("expected two records in: "^(String.escaped seq))
+
+let test_estimated_sizes () =
+  let check name results =
+    Alcotest.(check int) name (String.length (Zhttp_json.json_of_results results)) (Zhttp_json.json_size_of_results results);
+    Alcotest.(check int) (name^" (seq)") (String.length (Zhttp_json.json_seq_of_results results))
+      (Zhttp_json.json_seq_size_of_results results)
+  in
+  check "no result" [];
+  check "one result" [ result "/a/1" "x" ];
+  check "many results" [ result "/a/1" "x"; result ~encoding:Zhttp_json.encoding_json "/a/2" "[1, 2]"; result "/a/3" "\x00" ]
+
+(* The bytes allocated by the rendering in the reused buffer, compared to a concatenation of the rendered results *)
+let test_render_allocations () =
+  let results = Array.to_list (Array.init 100 (fun i -> result ~encoding:0x02L (Printf.sprintf "/a/%d" i) "value")) in
+  let allocated f =
+    let before = Gc.allocated_bytes () in
+    ignore (Sys.opaque_identity (f ()));
+    Gc.allocated_bytes () -. before
+  in
+  let concatenated () = "[\n"^(String.concat ",\n" (List.map (fun r -> Zhttp_json.json_of_result r) results))^"\n]" in
+  Alcotest.(check string) "same rendering" (concatenated ()) (Zhttp_json.json_of_results results);
+  let rendered = allocated (fun () -> Zhttp_json.json_of_results results) in
+  let concatenation = allocated concatenated in
+  Alcotest.(check bool) (Printf.sprintf "fewer bytes allocated (%.0f vs %.0f)" rendered concatenation) true
+    (rendered < concatenation)
+
+let test_line () =
+  Alcotest.(check string) "text" "a\\nb" (Zhttp_json.line_of_result (result ~encoding:0x02L "/a/b" "a\nb"));
+  Alcotest.(check string) "raw as hex" "00ff" (Zhttp_json.line_of_result (result "/a/b" "\x00\xff"));
+  Alcotest.(check string) "with key" "/a/b x" (Zhttp_json.line_of_result ~with_key:true (result ~encoding:0x02L "/a/b" "x"))
+
+let test_json_pointer () =
+  let json = Yojson.Safe.from_string {|{ "a": { "b": [ 10, { "c": "x" } ] }, "m~n": 1, "p/q": 2 }|} in
+  let check msg expected pointer =
+    Alcotest.(check (option string)) msg expected
+      (match Zhttp_json.json_pointer pointer json with Some v -> Some (Yojson.Safe.to_string v) | None -> None)
+  in
+  check "whole document" (Some (Yojson.Safe.to_string json)) "";
+  check "nested field" (Some "\"x\"") "/a/b/1/c";
+  check "array index" (Some "10") "/a/b/0";
+  check "escaped ~" (Some "1") "/m~0n";
+  check "escaped /" (Some "2") "/p~1q";
+  check "missing field" None "/a/z";
+  check "index out of bounds" None "/a/b/2";
+  check "leading zero index" None "/a/b/01";
+  check "no leading /" None "a"
+
+let timed key time =
+  let ts = Ztypes.Timestamp.create
+    (Option.get @@ Uuid.of_string "00000000-0000-0000-0000-000000000001")
+    (Option.get @@ Ztypes.Timestamp.Time.of_string time) in
+  (key, Abuf.create 0, { Ztypes.empty_data_info with ts = Some ts })
+
+let keys results = List.map (fun (k, _, _) -> k) results
+
+let test_sort_by_key () =
+  let results = [ result "/a/3" "3"; result "/a/1" "1"; result "/a/2" "2" ] in
+  Alcotest.(check (list string)) "ascending keys" [ "/a/1"; "/a/2"; "/a/3" ]
+    (keys @@ Zhttp_json.sort_results `Key results)
+
+let test_sort_by_time () =
+  let results = [ timed "/a/1" "20"; timed "/a/3" "10"; timed "/a/2" "30"; timed "/a/0" "10" ] in
+  Alcotest.(check (list string)) "ascending times" [ "/a/0"; "/a/3"; "/a/1"; "/a/2" ]
+    (keys @@ Zhttp_json.sort_results `Time results)
+
+let test_sort_by_time_desc () =
+  let results = [ timed "/a/1" "20"; timed "/a/3" "10"; timed "/a/2" "30"; timed "/a/0" "10" ] in
+  Alcotest.(check (list string)) "descending times" [ "/a/2"; "/a/1"; "/a/0"; "/a/3" ]
+    (keys @@ Zhttp_json.sort_results `Time_desc results)
+
+let test_json_diff () =
+  let json = Yojson.Safe.from_string in
+  let diff = Zhttp_json.json_diff (json "{\"a\": 1, \"b\": {\"c\": 2}, \"d\": 3}") (json "{\"a\": 1, \"b\": {\"c\": 4}, \"e\": 5}") in
+  Alcotest.(check string) "operations"
+    "[{\"op\":\"remove\",\"path\":\"/d\"},{\"op\":\"replace\",\"path\":\"/b/c\",\"value\":4},{\"op\":\"add\",\"path\":\"/e\",\"value\":5}]"
+    (Yojson.Safe.to_string (`List diff));
+  Alcotest.(check int) "equal values" 0 (List.length (Zhttp_json.json_diff (json "[1, 2]") (json "[1, 2]")))
+
+let test_line_diff () =
+  Alcotest.(check (option (list string))) "line diff" (Some [ "  a"; "- b"; "+ x"; "  c"; "+ d" ])
+    (Zhttp_json.line_diff "a\nb\nc" "a\nx\nc\nd");
+  Alcotest.(check (option (list string))) "too large" None (Zhttp_json.line_diff ~max_size:12 "a\nb\nc" "a\nx\nc\nd")
+
+let all_tests = [
+  "json_of_results with no result", `Quick, test_no_result;
+  "json_of_results with one result", `Quick, test_one_result;
+  "json_of_results with many results", `Quick, test_many_results;
+  "json_of_results fields order", `Quick, test_fields_order;
+  "json_of_results with a removal", `Quick, test_removal;
+  "json_of_default", `Quick, test_default;
+  "default result with the other shapes", `Quick, test_default_shapes;
+  "json_of_results with detail", `Quick, test_detail;
+  "json_map_of_results with duplicate keys", `Quick, test_map_shape;
+  "json_by_source_of_results", `Quick, test_by_source;
+  "json_with_meta", `Quick, test_meta;
+  "json_of_results with hex values", `Quick, test_hex;
+  "json_of_results with parsed values", `Quick, test_parse;
+  "json_seq_of_results", `Quick, test_json_seq;
+  "estimated sizes of the results", `Quick, test_estimated_sizes;
+  "allocations of the rendering", `Quick, test_render_allocations;
+  "line_of_result", `Quick, test_line;
+  "json_pointer", `Quick, test_json_pointer;
+  "json_diff", `Quick, test_json_diff;
+  "line_diff", `Quick, test_line_diff;
+  "sort_results by key", `Quick, test_sort_by_key;
+  "sort_results by time", `Quick, test_sort_by_time;
+  "sort_results by descending time", `Quick, test_sort_by_time_desc;
+]
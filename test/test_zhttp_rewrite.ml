@@ -0,0 +1,40 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+let rules = Zhttp_rewrite.[
+  rule "/v1/temp/*" "/sensors/temperature/*";
+  rule "/v1/*/status" "/devices/*/state";
+  rule "/v1/archive/**" "/storage/archive/**";
+]
+
+let check msg expected path =
+  Alcotest.(check string) msg expected (Zhttp_rewrite.rewrite rules path)
+
+let test_single_wildcard () =
+  check "temp" "/sensors/temperature/kitchen" "/v1/temp/kitchen";
+  check "status" "/devices/d1/state" "/v1/d1/status"
+
+let test_multi_wildcard () =
+  check "archive" "/storage/archive/2020/06/19" "/v1/archive/2020/06/19"
+
+let test_no_match () =
+  check "other path" "/v2/temp/kitchen" "/v2/temp/kitchen";
+  check "too many segments" "/v1/temp/kitchen/oven" "/v1/temp/kitchen/oven";
+  Alcotest.(check string) "no rule" "/v1/temp/kitchen" (Zhttp_rewrite.rewrite [] "/v1/temp/kitchen")
+
+let all_tests = [
+  "rewrite with * wildcards", `Quick, test_single_wildcard;
+  "rewrite with ** wildcard", `Quick, test_multi_wildcard;
+  "rewrite of non-matching paths", `Quick, test_no_match;
+]
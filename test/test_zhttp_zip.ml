@@ -0,0 +1,72 @@
+(*
+ * Copyright (c) 2017, 2020 ADLINK Technology Inc.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0, or the Apache License, Version 2.0
+ * which is available at https://www.apache.org/licenses/LICENSE-2.0.
+ *
+ * SPDX-License-Identifier: EPL-2.0 OR Apache-2.0
+ *
+ * Contributors:
+ *   ADLINK zenoh team, <zenoh@adlink-labs.tech>
+ *)
+
+let test_crc32 () =
+  Alcotest.(check int) "empty" 0 (Zhttp_zip.crc32 "");
+  Alcotest.(check int) "hello" 0x3610a686 (Zhttp_zip.crc32 "hello");
+  Alcotest.(check int) "check value" 0xcbf43926 (Zhttp_zip.crc32 "123456789")
+
+let test_entry_name () =
+  Alcotest.(check string) "key" "demo/a/b" (Zhttp_zip.entry_name "/demo/a/b");
+  Alcotest.(check string) "relative segments" "demo/b" (Zhttp_zip.entry_name "/demo/../b/.");
+  Alcotest.(check string) "invalid characters" "demo/a_b_c" (Zhttp_zip.entry_name "/demo/a:b*c");
+  Alcotest.(check string) "root" "_" (Zhttp_zip.entry_name "/")
+
+let entry zip key data = match Zhttp_zip.entry zip key data with
+  | Ok entry -> entry
+  | Error error -> Alcotest.fail error
+
+let test_archive () =
+  let zip = Zhttp_zip.create () in
+  let entry = entry zip "/a/b" "hello" in
+  let directory = Zhttp_zip.finish zip in
+  Alcotest.(check int) "entry size" (30 + 3 + 5) (String.length entry);
+  Alcotest.(check string) "entry signature" "PK\003\004" (String.sub entry 0 4);
+  Alcotest.(check string) "entry data" "a/bhello" (String.sub entry 30 8);
+  Alcotest.(check int) "directory size" (46 + 3 + 22) (String.length directory);
+  Alcotest.(check string) "directory signature" "PK\001\002" (String.sub directory 0 4);
+  Alcotest.(check string) "end signature" "PK\005\006" (String.sub directory 49 4);
+  Alcotest.(check int) "entries" 1 (Char.code directory.[49 + 10])
+
+let test_empty_archive () =
+  Alcotest.(check string) "empty" ("PK\005\006"^(String.make 18 '\000')) (Zhttp_zip.finish (Zhttp_zip.create ()))
+
+let is_error = function Ok _ -> false | Error _ -> true
+
+let test_archive_limits () =
+  let zip = Zhttp_zip.create ~max_size:250 () in
+  ignore (entry zip "/a" (String.make 50 'x'));
+  Alcotest.(check int) "archive size" (30 + 1 + 50 + 46 + 1 + 22) (Zhttp_zip.archive_size zip);
+  Alcotest.(check bool) "too large" true (is_error (Zhttp_zip.entry zip "/b" (String.make 100 'x')));
+  ignore (entry zip "/c" "");
+  Alcotest.(check int) "refused entry not counted" (2 * (30 + 1 + 46 + 1) + 50 + 22) (Zhttp_zip.archive_size zip)
+
+let test_unique_names () =
+  let zip = Zhttp_zip.create () in
+  let name key = let e = entry zip key "" in String.sub e 30 (Char.code e.[26] + 256 * Char.code e.[27]) in
+  Alcotest.(check string) "first" "a_b" (name "/a:b");
+  Alcotest.(check string) "sanitized collision" "a_b~1" (name "/a_b");
+  Alcotest.(check string) "second collision" "a_b~2" (name "/a*b");
+  Alcotest.(check string) "other name" "a/b" (name "/a/b");
+  Alcotest.(check string) "empty segment collision" "a/b~1" (name "/a//b");
+  Alcotest.(check string) "entries" "PK\005\006" (String.sub (Zhttp_zip.finish zip) (5 * 46 + 3 + 5 + 5 + 3 + 5) 4)
+
+let all_tests = [
+  "crc32", `Quick, test_crc32;
+  "entry_name", `Quick, test_entry_name;
+  "archive", `Quick, test_archive;
+  "empty archive", `Quick, test_empty_archive;
+  "archive limits", `Quick, test_archive_limits;
+  "unique entry names", `Quick, test_unique_names;
+]